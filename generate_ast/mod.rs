@@ -12,14 +12,24 @@ pub fn gerenate_ast(output_dir: &str) -> io::Result<()> {
     define_ast(
         output_dir,
         "Expr",
-        &["error", "token", "object", "rc"],
+        &["error", "token", "object", "stmt", "rc"],
         &[
             "Assign      : name Token, value Rc<Expr>",
             "Binary      : left Rc<Expr>, operator Token, right Rc<Expr>",
             "Call        : callee Rc<Expr>, paren Token, arguments Vec<Rc<Expr>>",
+            "Get         : object Rc<Expr>, name Token",
             "Grouping    : expression Rc<Expr>",
-            "Literal     : value Option<Object>",  
+            "Index       : object Rc<Expr>, bracket Token, index Rc<Expr>",
+            "IndexSet    : object Rc<Expr>, bracket Token, index Rc<Expr>, value Rc<Expr>",
+            "Interpolation : token Token, parts Vec<Rc<Expr>>",
+            "Lambda      : keyword Token, params Rc<Vec<Token>>, body Rc<Vec<Rc<Stmt>>>",
+            "List        : bracket Token, elements Vec<Rc<Expr>>, close_bracket Token",
+            "Literal     : value Option<Object>, token Token",  
             "Logical     : left Rc<Expr>, operator Token, right Rc<Expr>",
+            "Map         : brace Token, keys Vec<Rc<Expr>>, values Vec<Rc<Expr>>, close_brace Token",
+            "Set         : object Rc<Expr>, name Token, value Rc<Expr>",
+            "Super       : keyword Token, method Token",
+            "This        : keyword Token",
             "Unary       : operator Token, right Rc<Expr>",          
             "Variable    : name Token",
         ],
@@ -32,13 +42,15 @@ pub fn gerenate_ast(output_dir: &str) -> io::Result<()> {
         &[            
             "Block      : statements Rc<Vec<Rc<Stmt>>>",
             "Break      : token Token",
+            "Class      : name Token, superclass Option<Rc<VariableExpr>>, methods Rc<Vec<Rc<FunctionStmt>>>",
+            "Continue   : token Token",
             "Expression : expression Rc<Expr>",
             "Function   : name Token, params Rc<Vec<Token>>, body Rc<Vec<Rc<Stmt>>>",
             "If         : condition Rc<Expr>, then_branch Rc<Stmt>, else_branch Option<Rc<Stmt>>",
             "Print      : expression Rc<Expr>",
             "Return     : keyword Token, value Option<Rc<Expr>>",
             "Var        : name Token, initializer Option<Rc<Expr>>",
-            "While      : condition Rc<Expr>, body Rc<Stmt>"
+            "While      : condition Rc<Expr>, body Rc<Stmt>, increment Option<Rc<Expr>>"
         ],
     )?;
 
@@ -131,7 +143,7 @@ fn define_ast(
     writeln!(file, "impl {} {{", base_name)?;
     writeln!(
         file,
-        "    pub fn accept<T>(&self, wrapper:Rc<{}>, {}_visitor:  &dyn {}Visitor<T>) -> Result<T, LoxResult> {{",
+        "    pub fn accept<T, E>(&self, wrapper:Rc<{}>, {}_visitor:  &dyn {}Visitor<T, E>) -> Result<T, E> {{",
         base_name,
         base_name.to_lowercase(),
         base_name
@@ -162,11 +174,11 @@ fn define_ast(
         writeln!(file, "}}\n")?;
     }
 
-    writeln!(file, "pub trait {}Visitor<T> {{", base_name)?;
+    writeln!(file, "pub trait {}Visitor<T, E = LoxResult> {{", base_name)?;
     for ttype in &tree_type {
         writeln!(
             file,
-            "    fn visit_{0}_{1}(&self, wrapper: Rc<{2}>, {3}: &{4}) -> Result<T, LoxResult>;",
+            "    fn visit_{0}_{1}(&self, wrapper: Rc<{2}>, {3}: &{4}) -> Result<T, E>;",
             ttype.base_class_name.to_lowercase(),
             base_name.to_lowercase(),
             base_name,
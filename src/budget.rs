@@ -0,0 +1,198 @@
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+// Reading the clock costs more than a step, so the deadline is only checked
+// every this many steps.
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+// The limit a script ran into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+    Steps,
+    Time,
+    Memory,
+}
+
+// How much work a single run may do, so that hosts can run scripts they
+// don't trust. Every statement and expression the interpreter evaluates, or
+// instruction the VM executes, is one step. Memory is an estimate of the
+// bytes taken by the strings, collections, instances and environments the
+// run made. What a call or block environment took is given back when it
+// ends, unless a closure captured it; everything else counts until the end
+// of the run. No limit is set by default.
+#[derive(Debug, Default)]
+pub struct Budget {
+    max_steps: Cell<Option<u64>>,
+    time_limit: Cell<Option<Duration>>,
+    max_memory: Cell<Option<usize>>,
+    steps: Cell<u64>,
+    deadline: Cell<Option<Instant>>,
+    allocated: Cell<usize>,
+}
+
+impl Budget {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_max_steps(&self, steps: Option<u64>) {
+        self.max_steps.set(steps);
+    }
+
+    pub fn set_time_limit(&self, limit: Option<Duration>) {
+        self.time_limit.set(limit);
+    }
+
+    pub fn set_max_memory(&self, bytes: Option<usize>) {
+        self.max_memory.set(bytes);
+    }
+
+    // Gives a new run the whole budget.
+    pub fn start(&self) {
+        self.steps.set(0);
+        self.allocated.set(0);
+        self.deadline.set(self.time_limit.get().map(|limit| Instant::now() + limit));
+    }
+
+    pub fn step(&self) -> Result<(), Resource> {
+        let steps = self.steps.get() + 1;
+        self.steps.set(steps);
+        if self.max_steps.get().is_some_and(|max| steps > max) {
+            return Err(Resource::Steps);
+        }
+        if steps.is_multiple_of(DEADLINE_CHECK_INTERVAL)
+            && self.deadline.get().is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return Err(Resource::Time);
+        }
+        Ok(())
+    }
+
+    // Called before making a value, so that a huge one is refused rather
+    // than built.
+    pub fn allocate(&self, bytes: usize) -> Result<(), Resource> {
+        let allocated = self.allocated.get().saturating_add(bytes);
+        self.allocated.set(allocated);
+        if self.max_memory.get().is_some_and(|max| allocated > max) {
+            return Err(Resource::Memory);
+        }
+        Ok(())
+    }
+
+    pub fn release(&self, bytes: usize) {
+        self.allocated.set(self.allocated.get().saturating_sub(bytes));
+    }
+
+    pub fn message(&self, resource: Resource) -> String {
+        match resource {
+            Resource::Steps => format!("Step limit of {} exceeded.", self.max_steps.get().unwrap_or_default()),
+            Resource::Time => format!(
+                "Time limit of {}ms exceeded.",
+                self.time_limit.get().unwrap_or_default().as_millis()
+            ),
+            Resource::Memory => format!(
+                "Memory limit of {} bytes exceeded.",
+                self.max_memory.get().unwrap_or_default()
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Lux;
+    use crate::error::LoxResult;
+    use crate::span::Span;
+
+    fn limit_hit(lux: &mut Lux, source: &str) -> Resource {
+        let errors = lux.run(source).unwrap_err();
+        match errors.as_slice() {
+            [LoxResult::ResourceLimit { resource, .. }] => *resource,
+            _ => panic!("expected a resource limit error, got {:?}", errors),
+        }
+    }
+
+    #[test]
+    fn endless_loops_run_out_of_steps() {
+        for use_vm in [false, true] {
+            let mut lux = Lux::new();
+            lux.use_vm = use_vm;
+            lux.interpreter.set_max_steps(Some(10_000));
+            assert_eq!(limit_hit(&mut lux, "while (true) {}"), Resource::Steps);
+            assert_eq!(limit_hit(&mut lux, "fun f() { while (true) {} } f();"), Resource::Steps);
+
+            // Each run gets the whole budget again.
+            lux.run("var total = 0; for (var i = 0; i < 100; i = i + 1) total = total + i;")
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn endless_loops_run_out_of_time() {
+        for use_vm in [false, true] {
+            let mut lux = Lux::new();
+            lux.use_vm = use_vm;
+            lux.interpreter.set_time_limit(Some(Duration::from_millis(50)));
+            let start = Instant::now();
+            assert_eq!(limit_hit(&mut lux, "var i = 0; while (true) { i = i + 1; }"), Resource::Time);
+            assert!(start.elapsed() < Duration::from_secs(5));
+        }
+    }
+
+    #[test]
+    fn growing_values_run_out_of_memory() {
+        for use_vm in [false, true] {
+            let mut lux = Lux::new();
+            lux.use_vm = use_vm;
+            lux.interpreter.set_max_memory(Some(1 << 20));
+            let doubling = "var s = \"x\"; while (true) s = s + s;";
+            assert_eq!(limit_hit(&mut lux, doubling), Resource::Memory);
+            let pushing = "var xs = []; while (true) xs.push(xs.len());";
+            assert_eq!(limit_hit(&mut lux, pushing), Resource::Memory);
+            let fields = "class A {} var all = []; var i = 0; while (true) { var a = A(); a.f = [i, i]; all.push(a); i = i + 1; }";
+            assert_eq!(limit_hit(&mut lux, fields), Resource::Memory);
+
+            lux.run("var s = \"x\"; for (var i = 0; i < 10; i = i + 1) s = s + s;").unwrap();
+        }
+    }
+
+    #[test]
+    fn constant_live_memory_fits_under_a_small_cap() {
+        for use_vm in [false, true] {
+            let mut lux = Lux::new();
+            lux.use_vm = use_vm;
+            lux.interpreter.set_max_memory(Some(4_000_000));
+            lux.run("fun id(x) { return x; } var n = 0; for (var i = 0; i < 200000; i = i + 1) n = id(n) + 1;")
+                .unwrap();
+
+            lux.interpreter.set_max_memory(Some(10_000));
+            lux.run("for (var i = 0; i < 100000; i = i + 1) { var a = i; var b = a + 1; }").unwrap();
+            // What is kept still counts.
+            let keeping = "var fs = []; for (var i = 0; i < 100000; i = i + 1) { var a = i; fs.push(fun () { return a; }); }";
+            assert_eq!(limit_hit(&mut lux, keeping), Resource::Memory);
+        }
+    }
+
+    #[test]
+    fn resource_limits_are_reported_at_the_offending_code() {
+        for use_vm in [false, true] {
+            let mut lux = Lux::new();
+            lux.use_vm = use_vm;
+            lux.interpreter.set_max_steps(Some(100));
+            let errors = lux.run("var x = 1;\nwhile (x > 0) x = x + 1;").unwrap_err();
+            let diagnostic = errors[0].diagnostic();
+            assert_eq!(diagnostic.kind, "resource-limit");
+            assert_eq!(diagnostic.message, "Step limit of 100 exceeded.");
+            assert_eq!(diagnostic.span.map(|span| span.line), Some(2));
+
+            let mut lux = Lux::new();
+            lux.use_vm = use_vm;
+            lux.interpreter.set_max_memory(Some(1000));
+            let errors = lux.run("var s = \"x\";\nwhile (true) s = s + s;").unwrap_err();
+            let diagnostic = errors[0].diagnostic();
+            assert_eq!(diagnostic.message, "Memory limit of 1000 bytes exceeded.");
+            assert_eq!(diagnostic.span, Some(Span::new(32, 1, 2, 20)));
+        }
+    }
+}
@@ -0,0 +1,23 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::span::Span;
+
+// One active Lox function call, kept by the interpreter so that runtime
+// errors can report how they were reached.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CallFrame {
+    pub function: Rc<str>,
+    // The closing `)` of the call.
+    pub call_site: Span,
+}
+
+impl fmt::Display for CallFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "in {}(), called at line {}", self.function, self.call_site.line)?;
+        if self.call_site.column > 0 {
+            write!(f, ", column {}", self.call_site.column)?;
+        }
+        Ok(())
+    }
+}
@@ -2,6 +2,8 @@ use crate::interpreter;
 use crate::interpreter::*;
 use crate::object::*;
 use crate::error::*;
+use crate::gc::*;
+use crate::lox_function::*;
 
 use std::fmt;
 use core::fmt::Debug;
@@ -43,6 +45,14 @@ impl Debug for Callable {
 pub trait LoxCallable {
     fn call(&self, interpreter:& Interpreter, arguments: Vec<Object>) -> Result<Object, LoxResult>;
     fn arity(&self) -> usize;
+    // The name calls are recorded under, shared rather than copied.
+    fn name(&self) -> Rc<str>;
+    // Lets the VM run compiled functions itself, rather than through `call`.
+    fn as_function(&self) -> Option<&LoxFunction> {
+        None
+    }
+    // Marks the environments the callable keeps alive.
+    fn trace(&self, _tracer: &mut Tracer) {}
 }
 
 
@@ -57,5 +67,13 @@ impl LoxCallable for Callable {
         self.arity
     }
 
+    fn name(&self) -> Rc<str> {
+        self.func.name()
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        self.func.trace(tracer)
+    }
+
 }
 
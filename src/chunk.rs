@@ -0,0 +1,130 @@
+use std::rc::Rc;
+
+use crate::object::Object;
+use crate::span::Span;
+use crate::token::Token;
+
+// One VM instruction. Operands index into the tables of the chunk holding
+// it: `constants`, `tokens` (names, and where to report errors), `functions`
+// and `classes`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpCode {
+    Constant(u32),
+    Nil,
+    Pop,
+    GetGlobal(u32),
+    SetGlobal(u32),
+    DefineGlobal(u32),
+    // Locals live on the stack; slots count from the start of the frame,
+    // where slot 0 holds `this`, or the function being called.
+    GetLocal(u32),
+    SetLocal(u32),
+    GetUpvalue(u32),
+    SetUpvalue(u32),
+    // Pops the local on top of the stack, first moving it into the upvalue
+    // of any closure that captured it.
+    CloseUpvalue,
+    // Jumps are absolute. The conditional ones leave the condition on the
+    // stack.
+    Jump(u32),
+    JumpIfFalse(u32),
+    JumpIfTrue(u32),
+    Unary(u32),
+    Binary(u32),
+    Print,
+    Call { paren: u32, argc: u32 },
+    Closure(u32),
+    // Leaves the superclass, when there is one, on the stack below the new
+    // class.
+    Class(u32),
+    GetProperty(u32),
+    // Fails unless the value on top of the stack can have fields.
+    CheckInstance(u32),
+    SetProperty(u32),
+    // Pops the superclass and `this`, pushing the superclass's method bound
+    // to `this`.
+    GetSuper(u32),
+    GetIndex(u32),
+    SetIndex(u32),
+    // Fails unless the value on top of the stack can be a map key.
+    CheckKey(u32),
+    List { bracket: u32, len: u32 },
+    Map { brace: u32, len: u32 },
+    Interpolate { token: u32, len: u32 },
+    Return,
+}
+
+// `spans` runs parallel to `code`, giving the source each instruction was
+// compiled from.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<OpCode>,
+    pub spans: Vec<Span>,
+    pub constants: Vec<Object>,
+    pub tokens: Vec<Token>,
+    pub functions: Vec<Rc<FunctionProto>>,
+    pub classes: Vec<ClassProto>,
+}
+
+// Where a closure finds a variable it captures when it is created: in a
+// local slot of the enclosing function, or among that function's upvalues.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UpvalueSource {
+    pub is_local: bool,
+    pub index: u32,
+}
+
+// A compiled function, from which the VM makes a `LoxFunction` capturing
+// `upvalues`.
+#[derive(Debug)]
+pub struct FunctionProto {
+    pub name: Token,
+    pub params: Rc<Vec<Token>>,
+    pub chunk: Rc<Chunk>,
+    pub upvalues: Vec<UpvalueSource>,
+}
+
+#[derive(Debug)]
+pub struct ClassProto {
+    pub name: Token,
+    // Where a bad superclass is reported, when there is one.
+    pub superclass: Option<Token>,
+    pub methods: Vec<Rc<FunctionProto>>,
+}
+
+impl Chunk {
+    pub fn emit(&mut self, op: OpCode, span: Span) -> usize {
+        self.code.push(op);
+        self.spans.push(span);
+        self.code.len() - 1
+    }
+
+    // Points the jump at `at` to the next instruction to be emitted.
+    pub fn patch_jump(&mut self, at: usize) {
+        let target = self.code.len() as u32;
+        match &mut self.code[at] {
+            OpCode::Jump(to) | OpCode::JumpIfFalse(to) | OpCode::JumpIfTrue(to) => *to = target,
+            op => panic!("{:?} is not a jump", op),
+        }
+    }
+
+    pub fn add_constant(&mut self, value: Object) -> u32 {
+        self.constants.push(value);
+        (self.constants.len() - 1) as u32
+    }
+
+    pub fn add_token(&mut self, token: &Token) -> u32 {
+        self.tokens.push(token.dup());
+        (self.tokens.len() - 1) as u32
+    }
+
+    pub fn add_function(&mut self, function: FunctionProto) -> u32 {
+        self.functions.push(Rc::new(function));
+        (self.functions.len() - 1) as u32
+    }
+
+    pub fn add_class(&mut self, class: ClassProto) -> u32 {
+        self.classes.push(class);
+        (self.classes.len() - 1) as u32
+    }
+}
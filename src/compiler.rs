@@ -0,0 +1,604 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use crate::chunk::*;
+use crate::error::*;
+use crate::expr::*;
+use crate::object::*;
+use crate::span::Span;
+use crate::stmt::*;
+use crate::token::*;
+use crate::token_type::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer,
+}
+
+// A variable in a stack slot of the function being compiled.
+struct Local {
+    name: Rc<str>,
+    depth: usize,
+    // Whether a closure captured it, so it must be moved off the stack when
+    // its scope ends.
+    captured: bool,
+}
+
+// The jumps out of the innermost loop being compiled, patched once its end
+// is known.
+struct Loop {
+    scope_depth: usize,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+// Compiles resolved statements into a chunk for the VM, one compiler per
+// function. Locals get stack slots and captured variables become upvalues;
+// only globals are left to be looked up by name. The resolver has already
+// rejected the programs where that would differ from the tree walker.
+pub struct Compiler<'a> {
+    enclosing: Option<&'a Compiler<'a>>,
+    kind: FunctionKind,
+    chunk: RefCell<Chunk>,
+    locals: RefCell<Vec<Local>>,
+    upvalues: RefCell<Vec<UpvalueSource>>,
+    loops: RefCell<Vec<Loop>>,
+    scope_depth: Cell<usize>,
+    // Where the code being compiled came from, recorded for each instruction.
+    span: Cell<Span>,
+}
+
+impl<'a> Default for Compiler<'a> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> Compiler<'a> {
+    pub fn new() -> Self {
+        Compiler::for_function(None, FunctionKind::Script, Span::default())
+    }
+
+    fn for_function(enclosing: Option<&'a Compiler<'a>>, kind: FunctionKind, span: Span) -> Self {
+        // Slot 0 holds the receiver in methods, and is out of reach
+        // elsewhere.
+        let receiver = match kind {
+            FunctionKind::Method | FunctionKind::Initializer => "this",
+            FunctionKind::Script | FunctionKind::Function => "",
+        };
+        Self {
+            enclosing,
+            kind,
+            chunk: RefCell::new(Chunk::default()),
+            locals: RefCell::new(vec![Local { name: receiver.into(), depth: 0, captured: false }]),
+            upvalues: RefCell::new(Vec::new()),
+            loops: RefCell::new(Vec::new()),
+            scope_depth: Cell::new(if kind == FunctionKind::Script { 0 } else { 1 }),
+            span: Cell::new(span),
+        }
+    }
+
+    // Compiles a script into a function taking no arguments.
+    pub fn compile(self, statements: &[Rc<Stmt>]) -> Result<FunctionProto, LoxResult> {
+        for statement in statements {
+            self.statement(statement)?;
+        }
+        let name = Token::new(TokenType::Identifier, "script".to_string(), None, 0);
+        Ok(self.finish(name, &Rc::new(Vec::new())))
+    }
+
+    fn function(
+        &self,
+        kind: FunctionKind,
+        name: Token,
+        params: &Rc<Vec<Token>>,
+        body: &[Rc<Stmt>],
+    ) -> Result<FunctionProto, LoxResult> {
+        let compiler = Compiler::for_function(Some(self), kind, name.span);
+        for param in params.iter() {
+            compiler.add_local(&param.lexeme);
+        }
+        for statement in body {
+            compiler.statement(statement)?;
+        }
+        Ok(compiler.finish(name, params))
+    }
+
+    fn finish(self, name: Token, params: &Rc<Vec<Token>>) -> FunctionProto {
+        self.emit_return();
+        FunctionProto {
+            name,
+            params: Rc::clone(params),
+            chunk: Rc::new(self.chunk.into_inner()),
+            upvalues: self.upvalues.into_inner(),
+        }
+    }
+
+    fn statement(&self, stmt: &Rc<Stmt>) -> Result<(), LoxResult> {
+        let previous = self.span.get();
+        if let Some(span) = stmt.span() {
+            self.span.set(span);
+        }
+        let result = stmt.accept(Rc::clone(stmt), self);
+        self.span.set(previous);
+        result
+    }
+
+    fn expression(&self, expr: &Rc<Expr>) -> Result<(), LoxResult> {
+        let previous = self.span.replace(expr.span());
+        let result = expr.accept(Rc::clone(expr), self);
+        self.span.set(previous);
+        result
+    }
+
+    fn emit(&self, op: OpCode) -> usize {
+        self.chunk.borrow_mut().emit(op, self.span.get())
+    }
+
+    // An initializer always returns `this`; anything else returns nil unless
+    // it says otherwise.
+    fn emit_return(&self) {
+        if self.kind == FunctionKind::Initializer {
+            self.emit(OpCode::GetLocal(0));
+        } else {
+            self.emit(OpCode::Nil);
+        }
+        self.emit(OpCode::Return);
+    }
+
+    fn token(&self, token: &Token) -> u32 {
+        self.chunk.borrow_mut().add_token(token)
+    }
+
+    fn here(&self) -> u32 {
+        self.chunk.borrow().code.len() as u32
+    }
+
+    fn patch_jump(&self, at: usize) {
+        self.chunk.borrow_mut().patch_jump(at);
+    }
+
+    fn begin_scope(&self) {
+        self.scope_depth.set(self.scope_depth.get() + 1);
+    }
+
+    fn end_scope(&self) {
+        self.scope_depth.set(self.scope_depth.get() - 1);
+        loop {
+            let captured = match self.locals.borrow().last() {
+                Some(local) if local.depth > self.scope_depth.get() => local.captured,
+                _ => break,
+            };
+            self.emit(if captured { OpCode::CloseUpvalue } else { OpCode::Pop });
+            self.locals.borrow_mut().pop();
+        }
+    }
+
+    // Gives the value on top of the stack a name in the current scope,
+    // returning its slot.
+    fn add_local(&self, name: &Rc<str>) -> u32 {
+        let mut locals = self.locals.borrow_mut();
+        locals.push(Local {
+            name: Rc::clone(name),
+            depth: self.scope_depth.get(),
+            captured: false,
+        });
+        (locals.len() - 1) as u32
+    }
+
+    fn is_global_scope(&self) -> bool {
+        self.scope_depth.get() == 0
+    }
+
+    fn resolve_local(&self, name: &str) -> Option<u32> {
+        self.locals
+            .borrow()
+            .iter()
+            .rposition(|local| &*local.name == name)
+            .map(|slot| slot as u32)
+    }
+
+    fn resolve_upvalue(&self, name: &str) -> Option<u32> {
+        let enclosing = self.enclosing?;
+        if let Some(slot) = enclosing.resolve_local(name) {
+            enclosing.locals.borrow_mut()[slot as usize].captured = true;
+            return Some(self.add_upvalue(UpvalueSource { is_local: true, index: slot }));
+        }
+        let index = enclosing.resolve_upvalue(name)?;
+        Some(self.add_upvalue(UpvalueSource { is_local: false, index }))
+    }
+
+    fn add_upvalue(&self, source: UpvalueSource) -> u32 {
+        let mut upvalues = self.upvalues.borrow_mut();
+        if let Some(index) = upvalues.iter().position(|upvalue| *upvalue == source) {
+            return index as u32;
+        }
+        upvalues.push(source);
+        (upvalues.len() - 1) as u32
+    }
+
+    fn get_variable(&self, name: &Token) {
+        if let Some(slot) = self.resolve_local(&name.lexeme) {
+            self.emit(OpCode::GetLocal(slot));
+        } else if let Some(upvalue) = self.resolve_upvalue(&name.lexeme) {
+            self.emit(OpCode::GetUpvalue(upvalue));
+        } else {
+            let name = self.token(name);
+            self.emit(OpCode::GetGlobal(name));
+        }
+    }
+
+    // Reads `this` or `super`, which are never globals.
+    fn get_keyword(&self, name: &str) -> Result<(), LoxResult> {
+        if let Some(slot) = self.resolve_local(name) {
+            self.emit(OpCode::GetLocal(slot));
+        } else if let Some(upvalue) = self.resolve_upvalue(name) {
+            self.emit(OpCode::GetUpvalue(upvalue));
+        } else {
+            return Err(LoxResult::system_error(&format!("'{}' was not resolved.", name)));
+        }
+        Ok(())
+    }
+
+    // Leaves the scopes opened inside the innermost loop and jumps to the
+    // target chosen by `jumps`, once it is known.
+    fn loop_exit(&self, jumps: fn(&mut Loop) -> &mut Vec<usize>) {
+        let mut loops = self.loops.borrow_mut();
+        let Some(current) = loops.last_mut() else {
+            return;
+        };
+        // The locals stay declared: code after the jump is unreachable.
+        let exits: Vec<OpCode> = self
+            .locals
+            .borrow()
+            .iter()
+            .rev()
+            .take_while(|local| local.depth > current.scope_depth)
+            .map(|local| if local.captured { OpCode::CloseUpvalue } else { OpCode::Pop })
+            .collect();
+        for op in exits {
+            self.emit(op);
+        }
+        let jump = self.emit(OpCode::Jump(0));
+        jumps(current).push(jump);
+    }
+}
+
+impl<'a> StmtVisitor<()> for Compiler<'a> {
+    fn visit_block_stmt(&self, _: Rc<Stmt>, stmt: &BlockStmt) -> Result<(), LoxResult> {
+        self.begin_scope();
+        for statement in stmt.statements.iter() {
+            self.statement(statement)?;
+        }
+        self.end_scope();
+        Ok(())
+    }
+
+    fn visit_break_stmt(&self, _: Rc<Stmt>, _: &BreakStmt) -> Result<(), LoxResult> {
+        self.loop_exit(|current| &mut current.breaks);
+        Ok(())
+    }
+
+    fn visit_class_stmt(&self, _: Rc<Stmt>, stmt: &ClassStmt) -> Result<(), LoxResult> {
+        // A local class gets its slot first, so that its methods can refer
+        // to it.
+        let slot = if self.is_global_scope() {
+            None
+        } else {
+            self.emit(OpCode::Nil);
+            Some(self.add_local(&stmt.name.lexeme))
+        };
+
+        // Methods reach the superclass through a `super` local around them.
+        let superclass = match &stmt.superclass {
+            Some(superclass) => {
+                self.begin_scope();
+                self.expression(&Rc::new(Expr::Variable(Rc::clone(superclass))))?;
+                self.add_local(&"super".into());
+                Some(superclass.name.dup())
+            }
+            None => None,
+        };
+        let mut methods = Vec::new();
+        for method in stmt.methods.iter() {
+            let kind = if &*method.name.lexeme == "init" {
+                FunctionKind::Initializer
+            } else {
+                FunctionKind::Method
+            };
+            methods.push(Rc::new(self.function(kind, method.name.dup(), &method.params, &method.body)?));
+        }
+        let has_superclass = superclass.is_some();
+        let class = self.chunk.borrow_mut().add_class(ClassProto {
+            name: stmt.name.dup(),
+            superclass,
+            methods,
+        });
+        self.emit(OpCode::Class(class));
+
+        match slot {
+            Some(slot) => {
+                self.emit(OpCode::SetLocal(slot));
+                self.emit(OpCode::Pop);
+            }
+            None => {
+                let name = self.token(&stmt.name);
+                self.emit(OpCode::DefineGlobal(name));
+            }
+        }
+        if has_superclass {
+            self.end_scope();
+        }
+        Ok(())
+    }
+
+    fn visit_continue_stmt(&self, _: Rc<Stmt>, _: &ContinueStmt) -> Result<(), LoxResult> {
+        self.loop_exit(|current| &mut current.continues);
+        Ok(())
+    }
+
+    fn visit_expression_stmt(&self, _: Rc<Stmt>, stmt: &ExpressionStmt) -> Result<(), LoxResult> {
+        self.expression(&stmt.expression)?;
+        self.emit(OpCode::Pop);
+        Ok(())
+    }
+
+    fn visit_function_stmt(&self, _: Rc<Stmt>, stmt: &FunctionStmt) -> Result<(), LoxResult> {
+        // Declared before its body is compiled, so that it can call itself.
+        let global = self.is_global_scope();
+        if !global {
+            self.add_local(&stmt.name.lexeme);
+        }
+        let function = self.function(FunctionKind::Function, stmt.name.dup(), &stmt.params, &stmt.body)?;
+        let function = self.chunk.borrow_mut().add_function(function);
+        self.emit(OpCode::Closure(function));
+        if global {
+            let name = self.token(&stmt.name);
+            self.emit(OpCode::DefineGlobal(name));
+        }
+        Ok(())
+    }
+
+    fn visit_if_stmt(&self, _: Rc<Stmt>, stmt: &IfStmt) -> Result<(), LoxResult> {
+        self.expression(&stmt.condition)?;
+        let else_jump = self.emit(OpCode::JumpIfFalse(0));
+        self.emit(OpCode::Pop);
+        self.statement(&stmt.then_branch)?;
+        let end_jump = self.emit(OpCode::Jump(0));
+        self.patch_jump(else_jump);
+        self.emit(OpCode::Pop);
+        if let Some(else_branch) = &stmt.else_branch {
+            self.statement(else_branch)?;
+        }
+        self.patch_jump(end_jump);
+        Ok(())
+    }
+
+    fn visit_print_stmt(&self, _: Rc<Stmt>, stmt: &PrintStmt) -> Result<(), LoxResult> {
+        self.expression(&stmt.expression)?;
+        self.emit(OpCode::Print);
+        Ok(())
+    }
+
+    fn visit_return_stmt(&self, _: Rc<Stmt>, stmt: &ReturnStmt) -> Result<(), LoxResult> {
+        match &stmt.value {
+            Some(value) => {
+                self.expression(value)?;
+                self.emit(OpCode::Return);
+            }
+            None => self.emit_return(),
+        }
+        // The VM discards the frame's slots, closing any that were
+        // captured, so open scopes don't need closing.
+        Ok(())
+    }
+
+    fn visit_var_stmt(&self, _: Rc<Stmt>, stmt: &VarStmt) -> Result<(), LoxResult> {
+        match &stmt.initializer {
+            Some(initializer) => self.expression(initializer)?,
+            None => {
+                self.emit(OpCode::Nil);
+            }
+        }
+        if self.is_global_scope() {
+            let name = self.token(&stmt.name);
+            self.emit(OpCode::DefineGlobal(name));
+        } else {
+            self.add_local(&stmt.name.lexeme);
+        }
+        Ok(())
+    }
+
+    fn visit_while_stmt(&self, _: Rc<Stmt>, stmt: &WhileStmt) -> Result<(), LoxResult> {
+        let start = self.here();
+        self.expression(&stmt.condition)?;
+        let exit_jump = self.emit(OpCode::JumpIfFalse(0));
+        self.emit(OpCode::Pop);
+
+        self.loops.borrow_mut().push(Loop {
+            scope_depth: self.scope_depth.get(),
+            breaks: Vec::new(),
+            continues: Vec::new(),
+        });
+        let body = self.statement(&stmt.body);
+        let current = self.loops.borrow_mut().pop().unwrap();
+        body?;
+
+        for jump in current.continues {
+            self.patch_jump(jump);
+        }
+        if let Some(increment) = &stmt.increment {
+            self.expression(increment)?;
+            self.emit(OpCode::Pop);
+        }
+        self.emit(OpCode::Jump(start));
+
+        self.patch_jump(exit_jump);
+        self.emit(OpCode::Pop);
+        // A `break` skips the condition's Pop: it left nothing on the stack.
+        for jump in current.breaks {
+            self.patch_jump(jump);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> ExprVisitor<()> for Compiler<'a> {
+    fn visit_assign_expr(&self, _: Rc<Expr>, expr: &AssignExpr) -> Result<(), LoxResult> {
+        self.expression(&expr.value)?;
+        if let Some(slot) = self.resolve_local(&expr.name.lexeme) {
+            self.emit(OpCode::SetLocal(slot));
+        } else if let Some(upvalue) = self.resolve_upvalue(&expr.name.lexeme) {
+            self.emit(OpCode::SetUpvalue(upvalue));
+        } else {
+            let name = self.token(&expr.name);
+            self.emit(OpCode::SetGlobal(name));
+        }
+        Ok(())
+    }
+
+    fn visit_binary_expr(&self, _: Rc<Expr>, expr: &BinaryExpr) -> Result<(), LoxResult> {
+        self.expression(&expr.left)?;
+        self.expression(&expr.right)?;
+        let operator = self.token(&expr.operator);
+        self.emit(OpCode::Binary(operator));
+        Ok(())
+    }
+
+    fn visit_call_expr(&self, _: Rc<Expr>, expr: &CallExpr) -> Result<(), LoxResult> {
+        self.expression(&expr.callee)?;
+        for argument in &expr.arguments {
+            self.expression(argument)?;
+        }
+        let paren = self.token(&expr.paren);
+        self.emit(OpCode::Call { paren, argc: expr.arguments.len() as u32 });
+        Ok(())
+    }
+
+    fn visit_get_expr(&self, _: Rc<Expr>, expr: &GetExpr) -> Result<(), LoxResult> {
+        self.expression(&expr.object)?;
+        let name = self.token(&expr.name);
+        self.emit(OpCode::GetProperty(name));
+        Ok(())
+    }
+
+    fn visit_grouping_expr(&self, _: Rc<Expr>, expr: &GroupingExpr) -> Result<(), LoxResult> {
+        self.expression(&expr.expression)
+    }
+
+    fn visit_index_expr(&self, _: Rc<Expr>, expr: &IndexExpr) -> Result<(), LoxResult> {
+        self.expression(&expr.object)?;
+        self.expression(&expr.index)?;
+        let bracket = self.token(&expr.bracket);
+        self.emit(OpCode::GetIndex(bracket));
+        Ok(())
+    }
+
+    fn visit_indexset_expr(&self, _: Rc<Expr>, expr: &IndexSetExpr) -> Result<(), LoxResult> {
+        self.expression(&expr.object)?;
+        self.expression(&expr.index)?;
+        self.expression(&expr.value)?;
+        let bracket = self.token(&expr.bracket);
+        self.emit(OpCode::SetIndex(bracket));
+        Ok(())
+    }
+
+    fn visit_interpolation_expr(&self, _: Rc<Expr>, expr: &InterpolationExpr) -> Result<(), LoxResult> {
+        for part in &expr.parts {
+            self.expression(part)?;
+        }
+        let token = self.token(&expr.token);
+        self.emit(OpCode::Interpolate { token, len: expr.parts.len() as u32 });
+        Ok(())
+    }
+
+    fn visit_lambda_expr(&self, _: Rc<Expr>, expr: &LambdaExpr) -> Result<(), LoxResult> {
+        let name = Token::new(TokenType::Identifier, "lambda".to_string(), None, expr.keyword.span.line);
+        let function = self.function(FunctionKind::Function, name, &expr.params, &expr.body)?;
+        let function = self.chunk.borrow_mut().add_function(function);
+        self.emit(OpCode::Closure(function));
+        Ok(())
+    }
+
+    fn visit_list_expr(&self, _: Rc<Expr>, expr: &ListExpr) -> Result<(), LoxResult> {
+        for element in &expr.elements {
+            self.expression(element)?;
+        }
+        let bracket = self.token(&expr.bracket);
+        self.emit(OpCode::List { bracket, len: expr.elements.len() as u32 });
+        Ok(())
+    }
+
+    fn visit_literal_expr(&self, _: Rc<Expr>, expr: &LiteralExpr) -> Result<(), LoxResult> {
+        match &expr.value {
+            Some(value) => {
+                let constant = self.chunk.borrow_mut().add_constant(value.clone());
+                self.emit(OpCode::Constant(constant));
+            }
+            None => {
+                self.emit(OpCode::Nil);
+            }
+        }
+        Ok(())
+    }
+
+    fn visit_logical_expr(&self, _: Rc<Expr>, expr: &LogicalExpr) -> Result<(), LoxResult> {
+        self.expression(&expr.left)?;
+        let short_circuit = if expr.operator.is(&TokenType::Or) {
+            self.emit(OpCode::JumpIfTrue(0))
+        } else {
+            self.emit(OpCode::JumpIfFalse(0))
+        };
+        self.emit(OpCode::Pop);
+        self.expression(&expr.right)?;
+        self.patch_jump(short_circuit);
+        Ok(())
+    }
+
+    fn visit_map_expr(&self, _: Rc<Expr>, expr: &MapExpr) -> Result<(), LoxResult> {
+        let brace = self.token(&expr.brace);
+        for (key, value) in expr.keys.iter().zip(expr.values.iter()) {
+            self.expression(key)?;
+            self.emit(OpCode::CheckKey(brace));
+            self.expression(value)?;
+        }
+        self.emit(OpCode::Map { brace, len: expr.keys.len() as u32 });
+        Ok(())
+    }
+
+    fn visit_set_expr(&self, _: Rc<Expr>, expr: &SetExpr) -> Result<(), LoxResult> {
+        let name = self.token(&expr.name);
+        self.expression(&expr.object)?;
+        self.emit(OpCode::CheckInstance(name));
+        self.expression(&expr.value)?;
+        self.emit(OpCode::SetProperty(name));
+        Ok(())
+    }
+
+    fn visit_super_expr(&self, _: Rc<Expr>, expr: &SuperExpr) -> Result<(), LoxResult> {
+        self.get_keyword("this")?;
+        self.get_keyword("super")?;
+        let method = self.token(&expr.method);
+        self.emit(OpCode::GetSuper(method));
+        Ok(())
+    }
+
+    fn visit_this_expr(&self, _: Rc<Expr>, _: &ThisExpr) -> Result<(), LoxResult> {
+        self.get_keyword("this")
+    }
+
+    fn visit_unary_expr(&self, _: Rc<Expr>, expr: &UnaryExpr) -> Result<(), LoxResult> {
+        self.expression(&expr.right)?;
+        let operator = self.token(&expr.operator);
+        self.emit(OpCode::Unary(operator));
+        Ok(())
+    }
+
+    fn visit_variable_expr(&self, _: Rc<Expr>, expr: &VariableExpr) -> Result<(), LoxResult> {
+        self.get_variable(&expr.name);
+        Ok(())
+    }
+}
@@ -0,0 +1,401 @@
+use std::cell::{Cell, RefCell};
+use std::fmt::Write;
+use std::io::IsTerminal;
+
+use crate::error::LoxResult;
+use crate::span::Span;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Note {
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl Note {
+    pub fn new(message: &str, span: Option<Span>) -> Self {
+        Self {
+            message: message.to_string(),
+            span,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub kind: &'static str,
+    pub message: String,
+    pub span: Option<Span>,
+    pub notes: Vec<Note>,
+    pub help: Option<String>,
+}
+
+// Collects the errors found while scanning, parsing and resolving a script,
+// so that all of them can be reported instead of only the first.
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    errors: RefCell<Vec<LoxResult>>,
+    warnings: RefCell<Vec<Diagnostic>>,
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn report(&self, error: LoxResult) {
+        self.errors.borrow_mut().push(error);
+    }
+
+    // Warnings don't stop the script from running.
+    pub fn warn(&self, warning: Diagnostic) {
+        self.warnings.borrow_mut().push(warning);
+    }
+
+    pub fn take_warnings(&self) -> Vec<Diagnostic> {
+        let mut warnings = self.warnings.take();
+        warnings.sort_by_key(|warning| source_order(warning.span));
+        warnings
+    }
+
+    pub fn has_errors(&self) -> bool {
+        !self.errors.borrow().is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.errors.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.borrow().is_empty()
+    }
+
+    // Each phase reports its own errors in turn, so they are sorted back
+    // into the order they appear in the source.
+    pub fn into_errors(self) -> Vec<LoxResult> {
+        let mut errors = self.errors.into_inner();
+        errors.sort_by_key(|error| source_order(error.span()));
+        errors
+    }
+}
+
+// Diagnostics without a location go last.
+fn source_order(span: Option<Span>) -> (usize, usize) {
+    span.map_or((usize::MAX, usize::MAX), |span| (span.line, span.offset))
+}
+
+// How diagnostics are written to stderr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    Human,
+    // One JSON object per line, for editors and CI annotations.
+    Json,
+}
+
+impl ErrorFormat {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "human" => Some(ErrorFormat::Human),
+            "json" => Some(ErrorFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+struct SourceFile {
+    name: String,
+    text: String,
+}
+
+thread_local! {
+    // The script being run, so that errors reported while scanning, parsing,
+    // resolving or interpreting it can quote the offending line.
+    static SOURCE: RefCell<Option<SourceFile>> = const { RefCell::new(None) };
+    static FORMAT: Cell<ErrorFormat> = const { Cell::new(ErrorFormat::Human) };
+}
+
+pub fn set_error_format(format: ErrorFormat) {
+    FORMAT.with(|f| f.set(format));
+}
+
+pub fn error_format() -> ErrorFormat {
+    FORMAT.with(|f| f.get())
+}
+
+pub fn set_source(name: &str, text: &str) {
+    SOURCE.with(|source| {
+        source.replace(Some(SourceFile {
+            name: name.to_string(),
+            text: text.to_string(),
+        }));
+    });
+}
+
+pub fn emit(diagnostic: &Diagnostic) {
+    if error_format() == ErrorFormat::Json {
+        let json = SOURCE.with(|source| {
+            diagnostic.to_json(source.borrow().as_ref().map(|file| file.name.as_str()))
+        });
+        eprintln!("{}", json);
+        return;
+    }
+    let color = std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+    let rendered = SOURCE.with(|source| match &*source.borrow() {
+        Some(file) => diagnostic.render(&file.text, Some(&file.name), color),
+        None => diagnostic.render("", None, color),
+    });
+    eprint!("{}", rendered);
+}
+
+struct Style {
+    color: bool,
+}
+
+impl Style {
+    fn paint(&self, code: &str, text: &str) -> String {
+        if self.color {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+const RED: &str = "1;31";
+const YELLOW: &str = "1;33";
+const BLUE: &str = "1;34";
+const CYAN: &str = "1;36";
+const BOLD: &str = "1";
+
+impl Diagnostic {
+    pub fn error(kind: &'static str, message: &str, span: Option<Span>) -> Self {
+        Self {
+            severity: Severity::Error,
+            kind,
+            message: message.to_string(),
+            span,
+            notes: Vec::new(),
+            help: None,
+        }
+    }
+
+    pub fn warning(kind: &'static str, message: &str, span: Option<Span>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::error(kind, message, span)
+        }
+    }
+
+    // Renders the diagnostic rustc-style:
+    //
+    //     error[runtime]: Operands must be numbers.
+    //      --> script.lox:2:16
+    //       |
+    //     2 | print a + "x" - 2;
+    //       |               ^
+    //       = help: ...
+    pub fn render(&self, source: &str, file: Option<&str>, color: bool) -> String {
+        let style = Style { color };
+        let (label, label_color) = match self.severity {
+            Severity::Error => ("error", RED),
+            Severity::Warning => ("warning", YELLOW),
+        };
+
+        let lines: Vec<&str> = source.lines().collect();
+        let gutter = self
+            .span
+            .iter()
+            .chain(self.notes.iter().filter_map(|n| n.span.as_ref()))
+            .map(|span| span.line.to_string().len())
+            .max()
+            .unwrap_or(1);
+
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "{}{}",
+            style.paint(label_color, &format!("{}[{}]", label, self.kind)),
+            style.paint(BOLD, &format!(": {}", self.message))
+        );
+        if let Some(span) = &self.span {
+            render_snippet(&mut out, &style, &lines, file, span, gutter, label_color);
+        }
+        for note in &self.notes {
+            let _ = writeln!(
+                out,
+                "{} {}",
+                style.paint(CYAN, &format!("{:>width$}= note:", "", width = gutter + 1)),
+                note.message
+            );
+            if let Some(span) = &note.span {
+                render_snippet(&mut out, &style, &lines, file, span, gutter, CYAN);
+            }
+        }
+        if let Some(help) = &self.help {
+            let _ = writeln!(
+                out,
+                "{} {}",
+                style.paint(CYAN, &format!("{:>width$}= help:", "", width = gutter + 1)),
+                help
+            );
+        }
+        out
+    }
+
+    // A single-line JSON object; line and column are null when unknown.
+    pub fn to_json(&self, file: Option<&str>) -> String {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let line = self.span.map(|span| span.line);
+        let column = self.span.map(|span| span.column).filter(|column| *column > 0);
+        format!(
+            "{{\"file\":{},\"line\":{},\"column\":{},\"severity\":{},\"code\":{},\"message\":{}}}",
+            file.map_or("null".to_string(), json_string),
+            line.map_or("null".to_string(), |n| n.to_string()),
+            column.map_or("null".to_string(), |n| n.to_string()),
+            json_string(severity),
+            json_string(self.kind),
+            json_string(&self.message),
+        )
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn render_snippet(
+    out: &mut String,
+    style: &Style,
+    lines: &[&str],
+    file: Option<&str>,
+    span: &Span,
+    gutter: usize,
+    underline_color: &str,
+) {
+    let pad = " ".repeat(gutter);
+    let location = if span.column > 0 {
+        format!("{}:{}:{}", file.unwrap_or("<input>"), span.line, span.column)
+    } else {
+        format!("{}:{}", file.unwrap_or("<input>"), span.line)
+    };
+    let _ = writeln!(out, "{}{} {}", pad, style.paint(BLUE, "-->"), location);
+
+    // Without the source line there is nothing more useful to show.
+    let Some(text) = span.line.checked_sub(1).and_then(|i| lines.get(i)) else {
+        return;
+    };
+    let _ = writeln!(out, "{} {}", pad, style.paint(BLUE, "|"));
+    let _ = writeln!(
+        out,
+        "{} {}",
+        style.paint(BLUE, &format!("{:>width$} |", span.line, width = gutter)),
+        text.replace('\t', "    ")
+    );
+    if span.column == 0 {
+        return;
+    }
+
+    // Work in characters, expanding tabs the same way as the quoted line.
+    let before: String = text.chars().take(span.column - 1).collect();
+    let indent = before.chars().map(|c| if c == '\t' { 4 } else { 1 }).sum::<usize>();
+    let rest: String = text.chars().skip(span.column - 1).collect();
+    let underlined = rest
+        .char_indices()
+        .take_while(|(i, _)| *i < span.len)
+        .map(|(_, c)| if c == '\t' { 4 } else { 1 })
+        .sum::<usize>()
+        .max(1);
+    let _ = writeln!(
+        out,
+        "{} {} {}{}",
+        pad,
+        style.paint(BLUE, "|"),
+        " ".repeat(indent),
+        style.paint(underline_color, &"^".repeat(underlined))
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_the_line_and_underlines_the_span() {
+        let source = "var a = 1;\nprint a +  \"x\" - 2;\n";
+        let mut diagnostic =
+            Diagnostic::error("runtime", "Operands must be numbers.", Some(Span::new(26, 7, 2, 12)));
+        diagnostic.help = Some("convert the string with num()".to_string());
+        assert_eq!(
+            diagnostic.render(source, Some("test.lox"), false),
+            "error[runtime]: Operands must be numbers.\n \
+             --> test.lox:2:12\n  \
+             |\n\
+             2 | print a +  \"x\" - 2;\n  \
+             |            ^^^^^^^\n  \
+             = help: convert the string with num()\n"
+        );
+    }
+
+    #[test]
+    fn notes_point_at_their_own_span() {
+        let source = "{\n  var a = 1;\n  var a = 2;\n}";
+        let mut diagnostic = Diagnostic::error(
+            "resolve",
+            "Already a variable with this name in this scope",
+            Some(Span::new(19, 1, 3, 7)),
+        );
+        diagnostic.notes.push(Note::new("previously declared here", Some(Span::new(6, 1, 2, 7))));
+        let rendered = diagnostic.render(source, None, false);
+        assert!(rendered.contains("--> <input>:3:7\n  |\n3 |   var a = 2;\n  |       ^\n"));
+        assert!(rendered.contains("= note: previously declared here\n --> <input>:2:7\n"));
+    }
+
+    #[test]
+    fn uses_ansi_colour_only_when_asked() {
+        let diagnostic = Diagnostic::error("system", "boom", None);
+        assert!(!diagnostic.render("", None, false).contains('\x1b'));
+        assert!(diagnostic.render("", None, true).contains("\x1b[1;31merror[system]\x1b[0m"));
+    }
+
+    #[test]
+    fn json_output_escapes_strings() {
+        let diagnostic = Diagnostic::error(
+            "syntax",
+            "Invalid escape sequence '\\q'.\n",
+            Some(Span::new(9, 2, 3, 10)),
+        );
+        assert_eq!(
+            diagnostic.to_json(Some("dir\\a \"b\".lox")),
+            r#"{"file":"dir\\a \"b\".lox","line":3,"column":10,"severity":"error","code":"syntax","message":"Invalid escape sequence '\\q'.\n"}"#
+        );
+        assert_eq!(
+            Diagnostic::error("system", "\u{1}", None).to_json(None),
+            r#"{"file":null,"line":null,"column":null,"severity":"error","code":"system","message":"\u0001"}"#
+        );
+    }
+}
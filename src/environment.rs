@@ -1,10 +1,21 @@
-use crate::{error::LoxResult, object::*, token::*};
-use std::{cell::RefCell, collections::{hash_map::Entry, HashMap}};
+use crate::{error::LoxResult, gc::*, object::*, token::*};
+use std::{cell::RefCell, collections::HashMap};
 use std::rc::Rc;
 
+// Where the resolver found a local: how many environments out from the
+// current one, and its slot in that environment.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Binding {
+    pub depth: usize,
+    pub slot: usize,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Environment {
-    values: HashMap<String, Object>,
+    // Globals are late bound, so they are looked up by name. Locals live in
+    // `slots`, in the order the resolver numbered them.
+    values: HashMap<Rc<str>, Object>,
+    slots: Vec<Object>,
     enclosing: Option<Rc<RefCell<Environment>>>,
 }
 
@@ -12,6 +23,7 @@ impl Environment {
     pub fn new() -> Self {
         Self {
             values: HashMap::new(),
+            slots: Vec::new(),
             enclosing: None,
         }
     }
@@ -19,17 +31,27 @@ impl Environment {
     pub fn new_with_enclosing(enclosing: Rc<RefCell<Environment>>) -> Self {
         Environment { 
             values: HashMap::new(), 
+            slots: Vec::new(),
             enclosing: Some(enclosing),
         }
     }
 
-    pub fn define(&mut self, name: &String, value: Object) {
-        self.values.insert(name.to_string(), value);
+    pub fn define(&mut self, name: Rc<str>, value: Object) {
+        self.values.insert(name, value);
+    }
+
+    // Defines the next local; locals are defined in the same order the
+    // resolver declared them.
+    pub fn push(&mut self, value: Object) {
+        self.slots.push(value);
+    }
+
+    pub fn slot_count(&self) -> usize {
+        self.slots.len()
     }
 
     pub fn get(&self, name: &Token) -> Result<Object, LoxResult> {
-        //let name = &token.lexeme;
-        if let Some(object) = self.values.get(&name.as_string()) {
+        if let Some(object) = self.values.get(&*name.lexeme) {
             Ok(object.clone())
         } else if let Some(enclosing) = &self.enclosing{
             enclosing.borrow().get(name)
@@ -43,36 +65,51 @@ impl Environment {
     }
 
 
-    pub fn get_at(&self, distance:usize, name: &str)  -> Result<Object, LoxResult> {     
+    pub fn get_at(&self, distance:usize, slot: usize)  -> Result<Object, LoxResult> {     
         if distance == 0 {
-            Ok(self.values.get(name).unwrap().clone())
+            self.slots
+                .get(slot)
+                .cloned()
+                .ok_or_else(|| LoxResult::system_error(&format!("Local slot {} is not defined.", slot)))
         } else {
-            self.enclosing
-            .as_ref()
-            .unwrap()
+            self.enclosing()?
             .borrow()
-            .get_at(distance -1, name)
+            .get_at(distance -1, slot)
         }
     }
     
-    pub fn assign_at(&mut self, distance:usize, name:&Token, value:Object) -> Result<(), LoxResult> {
+    pub fn assign_at(&mut self, distance:usize, slot: usize, value:Object) -> Result<(), LoxResult> {
          if distance == 0 {
-            self.values.insert(name.as_string(), value);
-            Ok(())
+            match self.slots.get_mut(slot) {
+                Some(local) => {
+                    *local = value;
+                    Ok(())
+                }
+                None => Err(LoxResult::system_error(&format!("Local slot {} is not defined.", slot))),
+            }
         } else {
-            self.enclosing
-                .as_ref()
-                .unwrap()
+            self.enclosing()?
                 .borrow_mut()
-                .assign_at(distance - 1, name, value)
+                .assign_at(distance - 1, slot, value)
         }
     }
+
+    // Empties the environment, returning what it held. The collector uses
+    // this to break the cycles unreachable closures are part of.
+    pub fn clear(&mut self) -> Environment {
+        std::mem::replace(self, Environment::new())
+    }
+
+    fn enclosing(&self) -> Result<&Rc<RefCell<Environment>>, LoxResult> {
+        self.enclosing
+            .as_ref()
+            .ok_or_else(|| LoxResult::system_error("Local resolved past the global scope."))
+    }
     
 
     pub fn assign(&mut self, name: &Token, value: Object) -> Result<(), LoxResult> {
-        if let Entry::Occupied(mut object) = self
-        .values.entry(name.as_string()) {
-            object.insert(value);
+        if let Some(object) = self.values.get_mut(&*name.lexeme) {
+            *object = value;
             Ok(())
         } else if let Some(enclosing) = &self.enclosing {
             enclosing.borrow_mut().assign(name, value)
@@ -88,6 +125,17 @@ impl Environment {
     
 }
 
+impl Trace for Environment {
+    fn trace(&self, tracer: &mut Tracer) {
+        for value in self.values.values().chain(self.slots.iter()) {
+            tracer.object(value);
+        }
+        if let Some(enclosing) = &self.enclosing {
+            tracer.environment(enclosing);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::ops::Deref;
@@ -98,11 +146,11 @@ mod tests {
     #[test]
     fn can_define_a_variable() {
         let mut e = Environment::new();
-        e.define(&"One".to_string(), Object::Bool(true));
+        e.define("One".into(), Object::Bool(true));
 
         assert!(e.values.contains_key("One"));
         assert_eq!(
-            *e.values.get(&"One".to_string()).unwrap(),
+            *e.values.get("One").unwrap(),
             Object::Bool(true)
         )
     }
@@ -110,12 +158,12 @@ mod tests {
     #[test]
     fn can_redefine_a_variable() {
         let mut e = Environment::new();
-        e.define(&"Two".to_string(), Object::Bool(true));
-        e.define(&"Two".to_string(), Object::Num(12.0));
+        e.define("Two".into(), Object::Bool(true));
+        e.define("Two".into(), Object::Num(12.0));
 
         assert!(e.values.contains_key("Two"));
         assert_eq!(
-            e.values.get(&"Two".to_string()).unwrap(),
+            e.values.get("Two").unwrap(),
             &Object::Num(12.0)
         )
     }
@@ -123,9 +171,9 @@ mod tests {
     #[test]
     fn can_lookup_a_variable() {
         let mut e = Environment::new();
-        e.define(&"Three".to_string(), Object::Str("foo".to_string()));
+        e.define("Three".into(), Object::Str("foo".into()));
         let three_tok = Token::new(TokenType::Identifier, "Three".to_string(), None, 0);
-        assert_eq!(e.get(&three_tok).unwrap(), Object::Str("foo".to_string()));
+        assert_eq!(e.get(&three_tok).unwrap(), Object::Str("foo".into()));
     }
 
     #[test]
@@ -138,7 +186,7 @@ mod tests {
     #[test]
     fn error_when_assigning_undefined_variable() {
         let mut e = Environment::new();
-        //e.define(&"Three".to_string(),  Object::Str("foo".to_string()));
+        //e.define("Three".into(),  Object::Str("foo".into()));
         let four_tok = Token::new(TokenType::Identifier, "Four".to_string(), None, 0);
         assert!(e.assign(&four_tok, Object::Nil).is_err());
     }
@@ -146,7 +194,7 @@ mod tests {
     #[test]
     fn assigning_existing_defined_variable() {
         let mut e = Environment::new();
-        e.define(&"Four".to_string(), Object::Num(72.0));
+        e.define("Four".into(), Object::Num(72.0));
         let four_tok = Token::new(TokenType::Identifier, "Four".to_string(), None, 0);
         assert!(e.assign(&four_tok, Object::Num(89.4)).is_ok());
         assert_eq!(e.get(&four_tok).unwrap(), Object::Num(89.4));
@@ -162,7 +210,7 @@ mod tests {
     #[test]
     fn can_read_from_encolsed_environment() {
         let e = Rc::new(RefCell::new(Environment::new()));
-        e.borrow_mut().define(&"Five".to_string(), Object::Num(77.8));
+        e.borrow_mut().define("Five".into(), Object::Num(77.8));
         let f = Environment::new_with_enclosing(Rc::clone(&e));
         let five_tok = Token::new(TokenType::Identifier, "Five".to_string(), None, 0);
         assert_eq!(f.get(&five_tok).unwrap(), Object::Num(77.8));
@@ -171,9 +219,25 @@ mod tests {
     #[test]
     fn can_assign_to_encolsed_environment() {
         let e = Rc::new(RefCell::new(Environment::new()));
-        e.borrow_mut().define(&"Five".to_string(), Object::Num(77.8));
+        e.borrow_mut().define("Five".into(), Object::Num(77.8));
         let f = Environment::new_with_enclosing(Rc::clone(&e));
         let five_tok = Token::new(TokenType::Identifier, "Five".to_string(), None, 0);
         assert_eq!(f.get(&five_tok).unwrap(), Object::Num(77.8));
     }
+
+    #[test]
+    fn locals_are_found_by_depth_and_slot() {
+        let e = Rc::new(RefCell::new(Environment::new_with_enclosing(Rc::new(RefCell::new(Environment::new())))));
+        e.borrow_mut().push(Object::Num(1.0));
+        e.borrow_mut().push(Object::Num(2.0));
+        let mut f = Environment::new_with_enclosing(Rc::clone(&e));
+        f.push(Object::Bool(true));
+
+        assert_eq!(f.get_at(0, 0).unwrap(), Object::Bool(true));
+        assert_eq!(f.get_at(1, 1).unwrap(), Object::Num(2.0));
+        f.assign_at(1, 0, Object::Nil).unwrap();
+        assert_eq!(e.borrow().get_at(0, 0).unwrap(), Object::Nil);
+        assert!(f.get_at(0, 1).is_err());
+        assert!(f.get_at(3, 0).is_err());
+    }
 }
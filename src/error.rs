@@ -1,94 +1,165 @@
-use crate::token::{self, Token};
-use crate::object::*;
+use std::fmt;
+
+use crate::budget::Resource;
+use crate::call_frame::CallFrame;
+use crate::diagnostic::{self, Diagnostic, Note};
+use crate::span::Span;
+use crate::token::Token;
 use crate::token_type::TokenType;
 
 #[derive(Debug)]
 pub enum LoxResult {
-    ParseError {token:Token, message: String},
-    RuntimeError {token:Token, message: String},
-    Error {line:usize, message: String},
+    ParseError {token:Box<Token>, message: String, notes: Vec<Note>},
+    // Found by the resolver: the code parses but can't mean anything.
+    ResolveError {token:Box<Token>, message: String, notes: Vec<Note>},
+    // The backtrace lists the calls being made, innermost first.
+    RuntimeError {token:Box<Token>, message: String, notes: Vec<Note>, backtrace: Vec<CallFrame>},
+    Error {span:Span, message: String},
     SystemError {message:String},
-    RetrunValue {value: Object},
-    Break
+    // The script used more than the host allows; not a bug in the script.
+    ResourceLimit {resource: Resource, span: Option<Span>, message: String},
 }
 
-// #[derive(Debug)]
-// pub struct LoxError {
-//     token: Option<Token>,
-//     line: usize,
-//     message: String,
-// }
-
 impl LoxResult {
     pub fn error(line: usize, message: &str) -> LoxResult {
-        let err = LoxResult::Error {
-             line, 
-             message: message.to_string() 
-            } ;
-        
-        err.report("");
-        err
+        LoxResult::error_at(Span::at_line(line), message)
     }
 
-    pub fn parse_error(token: &Token, message: &str) -> LoxResult {
-        let err = LoxResult::ParseError { 
-            token: token.dup(),
+    pub fn error_at(span: Span, message: &str) -> LoxResult {
+        LoxResult::Error {
+            span,
             message: message.to_string()
-        }; 
-        err.report("");
-        err
+        }
+    }
+
+    pub fn parse_error(token: &Token, message: &str) -> LoxResult {
+        LoxResult::ParseError { 
+            token: Box::new(token.dup()),
+            message: message.to_string(),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn resolve_error(token: &Token, message: &str, notes: Vec<Note>) -> LoxResult {
+        LoxResult::ResolveError {
+            token: Box::new(token.dup()),
+            message: message.to_string(),
+            notes,
+        }
     }
 
     pub fn runtime_error(token: &Token, message: &str) -> LoxResult {
-        let err = LoxResult::RuntimeError {           
-            token: token.dup(),          
+        LoxResult::RuntimeError {           
+            token: Box::new(token.dup()),          
             message: message.to_string(),
-        };
-        err.report("");
-        err
+            notes: Vec::new(),
+            backtrace: Vec::new(),
+        }
     }
 
     pub fn system_error(message:&str) -> LoxResult {
-        let err = LoxResult::SystemError { message:  message.to_string()};
-        err.report("");
-        err
+        LoxResult::SystemError { message:  message.to_string()}
     }
 
-    pub fn return_value(value:Object) -> LoxResult {
-        LoxResult::RetrunValue { value }
+    pub fn resource_limit(resource: Resource, span: Option<Span>, message: &str) -> LoxResult {
+        LoxResult::ResourceLimit {
+            resource,
+            span,
+            message: message.to_string(),
+        }
     }
 
-    pub fn report(&self, loc: &str) {
+    pub fn diagnostic(&self) -> Diagnostic {
         match self {
-            LoxResult::ParseError { token, message } => {
-                if token.is(&TokenType::Eof) {
-                    eprintln!("[line {}] Error at end: {}", token.line, message);
+            LoxResult::ParseError { token, message, notes } | LoxResult::ResolveError { token, message, notes } => {
+                let kind = if matches!(self, LoxResult::ResolveError { .. }) { "resolve" } else { "syntax" };
+                let message = if token.is(&TokenType::Eof) {
+                    format!("{} (at end)", message)
                 } else {
-                    eprintln!(
-                        "[line {}] Error at '{}': {}",
-                        token.line,
-                        token.as_string(),
-                        message
-                    );
-                }
+                    format!("{} (at '{}')", message, token.as_string())
+                };
+                let mut diagnostic = Diagnostic::error(kind, &message, Some(token.span));
+                diagnostic.notes = notes.clone();
+                diagnostic
+            }
+            LoxResult::RuntimeError { token, message, notes, backtrace } => {
+                let mut diagnostic = Diagnostic::error("runtime", message, Some(token.span));
+                diagnostic.notes = notes.clone();
+                diagnostic.notes.extend(backtrace_notes(backtrace));
+                diagnostic
             }
-            LoxResult::RuntimeError { token, message } => {
+            LoxResult::Error { span, message } => Diagnostic::error("syntax", message, Some(*span)),
+            LoxResult::SystemError { message } => Diagnostic::error("system", message, None),
+            LoxResult::ResourceLimit { span, message, .. } => Diagnostic::error("resource-limit", message, *span),
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            LoxResult::ParseError { token, .. }
+            | LoxResult::ResolveError { token, .. }
+            | LoxResult::RuntimeError { token, .. } => Some(token.span),
+            LoxResult::Error { span, .. } => Some(*span),
+            LoxResult::SystemError { .. } => None,
+            LoxResult::ResourceLimit { span, .. } => *span,
+        }
+    }
+
+    pub fn report(&self) {
+        diagnostic::emit(&self.diagnostic());
+    }
+}
+
+// Deep recursion would bury the error, so only the ends of a long backtrace
+// are shown.
+const BACKTRACE_SHOWN: usize = 10;
+
+fn backtrace_notes(backtrace: &[CallFrame]) -> Vec<Note> {
+    let note = |frame: &CallFrame| Note::new(&frame.to_string(), None);
+    if backtrace.len() <= 2 * BACKTRACE_SHOWN {
+        return backtrace.iter().map(note).collect();
+    }
+    let hidden = backtrace.len() - 2 * BACKTRACE_SHOWN;
+    let mut notes: Vec<Note> = backtrace[..BACKTRACE_SHOWN].iter().map(note).collect();
+    notes.push(Note::new(&format!("... {} more calls ...", hidden), None));
+    notes.extend(backtrace[backtrace.len() - BACKTRACE_SHOWN..].iter().map(note));
+    notes
+}
+
+// The one-line form, used where there is no room for a snippet.
+impl fmt::Display for LoxResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoxResult::ParseError { token, message, .. }
+            | LoxResult::ResolveError { token, message, .. }
+            | LoxResult::RuntimeError { token, message, .. } => {
                 if token.is(&TokenType::Eof) {
-                    eprintln!("[line {}] Error at end: {}", token.line, message);
+                    write!(f, "[line {}] Error at end: {}", token.span.line, message)
                 } else {
-                    eprintln!("Line {} at '{}' {}", token.line, token.as_string(), message,);
+                    write!(
+                        f,
+                        "[line {}, column {}] Error at '{}': {}",
+                        token.span.line,
+                        token.span.column,
+                        token.as_string(),
+                        message
+                    )
                 }
             }
-            LoxResult::Error { line, message } => {
-                eprintln!("[line {}] Error{}: {}", line, loc, message);
+            LoxResult::Error { span, message } => {
+                write!(f, "[line {}] Error: {}", span.line, message)
             },
             LoxResult::SystemError { message } => {
-                eprintln!("System Error: {message}");
+                write!(f, "System Error: {message}")
             },
-            LoxResult::Break => {},
-            LoxResult::RetrunValue { value:_ } => {}
-            
-
-        };
+            LoxResult::ResourceLimit { span: Some(span), message, .. } => {
+                write!(f, "[line {}] Resource limit: {}", span.line, message)
+            },
+            LoxResult::ResourceLimit { span: None, message, .. } => {
+                write!(f, "Resource limit: {message}")
+            },
+        }
     }
 }
+
+impl std::error::Error for LoxResult {}
@@ -0,0 +1,43 @@
+use std::fmt;
+
+use crate::error::*;
+use crate::object::*;
+
+// How a statement can finish other than by running to completion. Only
+// `Error` is a real failure; the others unwind to the enclosing function call
+// or loop, which the resolver guarantees exists.
+#[derive(Debug)]
+pub enum ExecSignal {
+    Error(LoxResult),
+    Return(Object),
+    Break,
+    Continue,
+}
+
+impl From<LoxResult> for ExecSignal {
+    fn from(error: LoxResult) -> Self {
+        ExecSignal::Error(error)
+    }
+}
+
+impl ExecSignal {
+    // Turns a signal that escaped the construct meant to catch it into an
+    // error, e.g. a `break` reaching a function boundary.
+    pub fn into_error(self) -> LoxResult {
+        match self {
+            ExecSignal::Error(error) => error,
+            signal => LoxResult::system_error(&format!("Unexpected '{}'.", signal)),
+        }
+    }
+}
+
+impl fmt::Display for ExecSignal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExecSignal::Error(error) => write!(f, "{}", error),
+            ExecSignal::Return(_) => write!(f, "return"),
+            ExecSignal::Break => write!(f, "break"),
+            ExecSignal::Continue => write!(f, "continue"),
+        }
+    }
+}
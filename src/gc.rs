@@ -0,0 +1,484 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::rc::{Rc, Weak};
+
+use crate::callable::*;
+use crate::environment::*;
+use crate::lox_function::*;
+use crate::object::*;
+use crate::vm::{Upvalue, Upvalues};
+
+// Below this many tracked containers, neither pruning nor collecting is
+// worth the time.
+const MIN_THRESHOLD: usize = 1024;
+
+// Keeps track of the environments closures capture in the tree walker, and
+// the upvalues they capture in the VM, so that the ones left unreachable can
+// be emptied. A closure and the container holding it refer to each other, so
+// reference counting alone never frees them. Containers nothing closes over
+// can't be part of a cycle and aren't tracked.
+pub struct Heap {
+    environments: Registry<Environment>,
+    upvalues: Registry<Upvalue>,
+    // A collection runs at the next safe point once the registries grow this
+    // large.
+    collect_at: Cell<usize>,
+    // The most containers ever tracked at once, live or not.
+    #[cfg(test)]
+    peak: Cell<usize>,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self {
+            environments: Registry::default(),
+            upvalues: Registry::default(),
+            collect_at: Cell::new(MIN_THRESHOLD),
+            #[cfg(test)]
+            peak: Cell::new(0),
+        }
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn track(&self, environment: &Rc<RefCell<Environment>>) {
+        self.environments.track(environment);
+        #[cfg(test)]
+        self.peak.set(self.peak.get().max(self.environments.len() + self.upvalues.len()));
+    }
+
+    pub fn track_upvalue(&self, upvalue: &Rc<RefCell<Upvalue>>) {
+        self.upvalues.track(upvalue);
+        #[cfg(test)]
+        self.peak.set(self.peak.get().max(self.environments.len() + self.upvalues.len()));
+    }
+
+    // How many tracked containers are still alive.
+    pub fn live(&self) -> usize {
+        self.environments.live() + self.upvalues.live()
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.environments.len() + self.upvalues.len() >= self.collect_at.get()
+    }
+
+    // Empties every tracked container that nothing outside the heap can
+    // reach, returning how many there were.
+    //
+    // Values the running script holds on the native stack, the VM's stack or
+    // in the interpreter aren't visible from here, so rather than marking
+    // from known roots, this counts the references the containers hold to
+    // each other. Whatever has more references than that is held from
+    // outside, and is a root. So a collection is safe at any point where no
+    // container is being changed.
+    pub fn collect(&self) -> usize {
+        let mut tracer = Tracer::default();
+        self.environments.each(|environment| tracer.add(Node::Environment(environment)));
+        self.upvalues.each(|upvalue| tracer.add(Node::Upvalue(upvalue)));
+        tracer.trace_all();
+        let reachable = tracer.reachable();
+
+        let environments = self.environments.sweep(|environment| reachable.contains(&(Rc::as_ptr(environment) as *const ())));
+        let upvalues = self.upvalues.sweep(|upvalue| reachable.contains(&(Rc::as_ptr(upvalue) as *const ())));
+        let live = self.environments.len() + self.upvalues.len();
+        self.collect_at.set((live * 2).max(MIN_THRESHOLD));
+        // The tracer's references would keep everything alive.
+        drop(tracer);
+
+        // Emptying a container drops values that may free other containers,
+        // so nothing may stay borrowed while it happens.
+        let collected = environments.len() + upvalues.len();
+        let contents: Vec<Environment> = environments
+            .iter()
+            .filter_map(|environment| Some(environment.try_borrow_mut().ok()?.clear()))
+            .collect();
+        let values: Vec<Upvalue> = upvalues
+            .iter()
+            .filter_map(|upvalue| {
+                let mut upvalue = upvalue.try_borrow_mut().ok()?;
+                Some(std::mem::replace(&mut *upvalue, Upvalue::Closed(Object::Nil)))
+            })
+            .collect();
+        drop(contents);
+        drop(values);
+        collected
+    }
+}
+
+// Weak pointers to the containers of one kind that have been tracked.
+struct Registry<T> {
+    entries: RefCell<Vec<Weak<RefCell<T>>>>,
+    // Dropped containers are forgotten once the registry grows this large.
+    prune_at: Cell<usize>,
+}
+
+impl<T> Default for Registry<T> {
+    fn default() -> Self {
+        Self {
+            entries: RefCell::new(Vec::new()),
+            prune_at: Cell::new(MIN_THRESHOLD),
+        }
+    }
+}
+
+impl<T> Registry<T> {
+    fn track(&self, container: &Rc<RefCell<T>>) {
+        let mut entries = self.entries.borrow_mut();
+        // Methods and sibling closures often capture the same environment.
+        if entries.last().is_some_and(|last| std::ptr::eq(last.as_ptr(), Rc::as_ptr(container))) {
+            return;
+        }
+        entries.push(Rc::downgrade(container));
+        // A weak pointer keeps the container's allocation alive, so the dead
+        // ones can't wait for the next collection.
+        if entries.len() >= self.prune_at.get() {
+            entries.retain(|entry| entry.strong_count() > 0);
+            self.prune_at.set((entries.len() * 2).max(MIN_THRESHOLD));
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.entries.borrow().len()
+    }
+
+    fn live(&self) -> usize {
+        self.entries
+            .borrow()
+            .iter()
+            .filter(|entry| entry.strong_count() > 0)
+            .map(|entry| entry.as_ptr())
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    fn each(&self, mut visit: impl FnMut(Rc<RefCell<T>>)) {
+        for entry in self.entries.borrow().iter() {
+            if let Some(container) = entry.upgrade() {
+                visit(container);
+            }
+        }
+    }
+
+    // Forgets the dropped containers and the ones `keep` rejects, returning
+    // the latter.
+    fn sweep(&self, mut keep: impl FnMut(&Rc<RefCell<T>>) -> bool) -> Vec<Rc<RefCell<T>>> {
+        let mut garbage = Vec::new();
+        let mut seen = HashSet::new();
+        let mut entries = self.entries.borrow_mut();
+        entries.retain(|entry| {
+            let Some(container) = entry.upgrade() else {
+                return false;
+            };
+            if !seen.insert(Rc::as_ptr(&container)) {
+                return false;
+            }
+            if !keep(&container) {
+                garbage.push(container);
+                return false;
+            }
+            true
+        });
+        self.prune_at.set((entries.len() * 2).max(MIN_THRESHOLD));
+        garbage
+    }
+}
+
+// Implemented by everything that can refer to a container. `trace` must
+// report each reference it holds once, and nothing it doesn't hold: the
+// collector would free what is still in use if it counted too many.
+pub trait Trace {
+    fn trace(&self, tracer: &mut Tracer);
+}
+
+// Something that holds references: a container or a value, with the
+// tracer's own reference to it.
+#[derive(Clone)]
+enum Node {
+    Environment(Rc<RefCell<Environment>>),
+    Upvalue(Rc<RefCell<Upvalue>>),
+    Upvalues(Upvalues),
+    Method(Rc<LoxFunction>),
+    Value(Object),
+}
+
+impl Node {
+    fn pointer(&self) -> Option<*const ()> {
+        Some(match self {
+            Node::Environment(environment) => Rc::as_ptr(environment) as *const (),
+            Node::Upvalue(upvalue) => Rc::as_ptr(upvalue) as *const (),
+            Node::Upvalues(upvalues) => Rc::as_ptr(upvalues) as *const (),
+            Node::Method(method) => Rc::as_ptr(method) as *const (),
+            Node::Value(Object::Func(callable)) => Rc::as_ptr(&callable.func) as *const (),
+            Node::Value(Object::Class(class)) => Rc::as_ptr(class) as *const (),
+            Node::Value(Object::Instance(instance)) => Rc::as_ptr(instance) as *const (),
+            Node::Value(Object::List(list)) => Rc::as_ptr(list) as *const (),
+            Node::Value(Object::Map(map)) => Rc::as_ptr(map) as *const (),
+            Node::Value(_) => return None,
+        })
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Environment(environment) => Rc::strong_count(environment),
+            Node::Upvalue(upvalue) => Rc::strong_count(upvalue),
+            Node::Upvalues(upvalues) => Rc::strong_count(upvalues),
+            Node::Method(method) => Rc::strong_count(method),
+            Node::Value(Object::Func(callable)) => Rc::strong_count(&callable.func),
+            Node::Value(Object::Class(class)) => Rc::strong_count(class),
+            Node::Value(Object::Instance(instance)) => Rc::strong_count(instance),
+            Node::Value(Object::List(list)) => Rc::strong_count(list),
+            Node::Value(Object::Map(map)) => Rc::strong_count(map),
+            Node::Value(_) => 0,
+        }
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Node::Environment(environment) => match environment.try_borrow() {
+                Ok(environment) => environment.trace(tracer),
+                Err(_) => tracer.opaque(),
+            },
+            Node::Upvalue(upvalue) => match upvalue.try_borrow() {
+                Ok(upvalue) => {
+                    if let Upvalue::Closed(value) = &*upvalue {
+                        tracer.object(value);
+                    }
+                }
+                Err(_) => tracer.opaque(),
+            },
+            Node::Upvalues(upvalues) => {
+                for upvalue in upvalues.iter() {
+                    tracer.upvalue(upvalue);
+                }
+            }
+            Node::Method(method) => method.trace(tracer),
+            Node::Value(value) => value.trace(tracer),
+        }
+    }
+}
+
+// Finds every node reachable from the tracked containers, counting the
+// references between them. It works through an explicit list so that long
+// chains of values can't overflow the native stack.
+#[derive(Default)]
+pub struct Tracer {
+    index: HashMap<*const (), usize>,
+    nodes: Vec<Node>,
+    // How many references to each node the other nodes hold.
+    internal: Vec<usize>,
+    edges: Vec<Vec<usize>>,
+    // Nodes that couldn't be traced, so whatever they hold is unknown.
+    opaque: Vec<bool>,
+    // The node being traced.
+    current: Option<usize>,
+}
+
+impl Tracer {
+    pub fn environment(&mut self, environment: &Rc<RefCell<Environment>>) {
+        self.add(Node::Environment(Rc::clone(environment)));
+    }
+
+    pub fn upvalue(&mut self, upvalue: &Rc<RefCell<Upvalue>>) {
+        self.add(Node::Upvalue(Rc::clone(upvalue)));
+    }
+
+    pub fn upvalues(&mut self, upvalues: &Upvalues) {
+        self.add(Node::Upvalues(Rc::clone(upvalues)));
+    }
+
+    pub fn method(&mut self, method: &Rc<LoxFunction>) {
+        self.add(Node::Method(Rc::clone(method)));
+    }
+
+    pub fn object(&mut self, value: &Object) {
+        self.add(Node::Value(value.clone()));
+    }
+
+    // Says that the node being traced is being changed, so its references
+    // can't be counted.
+    pub fn opaque(&mut self) {
+        if let Some(current) = self.current {
+            self.opaque[current] = true;
+        }
+    }
+
+    fn add(&mut self, node: Node) {
+        let Some(pointer) = node.pointer() else {
+            return;
+        };
+        let index = match self.index.get(&pointer) {
+            Some(&index) => index,
+            None => {
+                self.index.insert(pointer, self.nodes.len());
+                self.nodes.push(node);
+                self.internal.push(0);
+                self.edges.push(Vec::new());
+                self.opaque.push(false);
+                self.nodes.len() - 1
+            }
+        };
+        if let Some(current) = self.current {
+            self.internal[index] += 1;
+            self.edges[current].push(index);
+        }
+    }
+
+    fn trace_all(&mut self) {
+        let mut next = 0;
+        while next < self.nodes.len() {
+            self.current = Some(next);
+            let node = self.nodes[next].clone();
+            node.trace(self);
+            next += 1;
+        }
+        self.current = None;
+    }
+
+    // The nodes something outside the heap can reach: those referenced more
+    // often than the other nodes account for, and everything they lead to.
+    fn reachable(&self) -> HashSet<*const ()> {
+        let mut marked = vec![false; self.nodes.len()];
+        let mut work: Vec<usize> = (0..self.nodes.len())
+            .filter(|&i| self.opaque[i] || self.nodes[i].strong_count() > self.internal[i] + 1)
+            .collect();
+        while let Some(i) = work.pop() {
+            if !marked[i] {
+                marked[i] = true;
+                work.extend(self.edges[i].iter().copied().filter(|&j| !marked[j]));
+            }
+        }
+        self.nodes
+            .iter()
+            .zip(marked)
+            .filter(|(_, marked)| *marked)
+            .filter_map(|(node, _)| node.pointer())
+            .collect()
+    }
+}
+
+impl Trace for Object {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Object::Func(callable) => callable.func.trace(tracer),
+            Object::Class(class) => class.trace(tracer),
+            Object::Instance(instance) => instance.trace(tracer),
+            Object::List(list) => match list.try_borrow() {
+                Ok(list) => {
+                    for element in list.iter() {
+                        tracer.object(element);
+                    }
+                }
+                Err(_) => tracer.opaque(),
+            },
+            Object::Map(map) => match map.try_borrow() {
+                Ok(map) => {
+                    for (_, value) in map.iter() {
+                        tracer.object(value);
+                    }
+                }
+                Err(_) => tracer.opaque(),
+            },
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::budget::Resource;
+    use crate::error::LoxResult;
+    use crate::Lux;
+
+    #[test]
+    fn closures_created_in_a_loop_are_reclaimed() {
+        for use_vm in [false, true] {
+            let mut lux = Lux::new();
+            lux.use_vm = use_vm;
+            lux.run(
+                "fun counter() {
+                    var n = 0;
+                    fun count() { n = n + 1; if (n < 3) count(); return n; }
+                    return count;
+                }
+                var kept = counter();
+                for (var i = 0; i < 20000; i = i + 1) {
+                    var c = counter();
+                    c();
+                }",
+            )
+            .unwrap();
+            // Each `counter()` call leaves an environment, or upvalues, that
+            // hold `count`, which closes over them. Collections keep up with
+            // the loop rather than waiting for it to end.
+            assert!(lux.interpreter.heap.peak.get() < 2 * MIN_THRESHOLD, "{} at peak", lux.interpreter.heap.peak.get());
+            assert!(lux.interpreter.heap.live() < 2 * MIN_THRESHOLD, "{} live", lux.interpreter.heap.live());
+
+            // What is still reachable survives.
+            lux.run("var counted = kept();").unwrap();
+            assert_eq!(lux.global("counted"), Object::Num(3.0));
+        }
+    }
+
+    #[test]
+    fn garbage_is_collected_during_a_long_call() {
+        for use_vm in [false, true] {
+            let mut lux = Lux::new();
+            lux.use_vm = use_vm;
+            lux.interpreter.set_max_steps(Some(200_000));
+            let errors = lux
+                .run("fun main() { while (true) { fun g() { return g; } } } main();")
+                .unwrap_err();
+            assert!(
+                matches!(errors.as_slice(), [LoxResult::ResourceLimit { resource: Resource::Steps, .. }]),
+                "{:?}",
+                errors
+            );
+            assert!(lux.interpreter.heap.peak.get() < 2 * MIN_THRESHOLD, "{} at peak", lux.interpreter.heap.peak.get());
+        }
+    }
+
+    #[test]
+    fn values_in_use_survive_a_collection() {
+        // The closures `make` returns are only held as arguments while
+        // `churn` forces collections.
+        for use_vm in [false, true] {
+            let mut lux = Lux::new();
+            lux.use_vm = use_vm;
+            lux.run(
+                "fun churn() {
+                    for (var i = 0; i < 3000; i = i + 1) { fun g() { return g; } }
+                    return 1;
+                }
+                fun make(n) { fun f() { return n + churn(); } return f; }
+                fun both(a, b) { return a() + b(); }
+                var total = 0;
+                for (var i = 0; i < 5; i = i + 1) total = total + both(make(1), make(2));",
+            )
+            .unwrap();
+            assert_eq!(lux.global("total"), Object::Num(25.0));
+        }
+    }
+
+    #[test]
+    fn reachable_cycles_are_kept() {
+        let mut lux = Lux::new();
+        lux.run(
+            "class Node { init(next) { this.next = next; this.me = this.get; } get() { return this; } }
+            var list = [];
+            list.push(list);
+            var nodes = {\"head\": Node(nil)};
+            for (var i = 0; i < 3000; i = i + 1) {
+                nodes[\"head\"] = Node(nodes[\"head\"]);
+            }",
+        )
+        .unwrap();
+        assert_eq!(lux.interpreter.heap.collect(), 0);
+        lux.run("var same = nodes[\"head\"].me() == nodes[\"head\"];").unwrap();
+        assert_eq!(lux.global("same"), Object::Bool(true));
+    }
+}
@@ -0,0 +1,87 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::rc::Rc;
+
+// How many symbols there may be before the first sweep.
+const MIN_SWEEP_AT: usize = 1024;
+
+// Hands out one shared copy of each distinct name or string, so that copying
+// tokens and string values only bumps a reference count. One interner lasts
+// a whole session, so names are shared between REPL lines too.
+#[derive(Debug)]
+pub struct Interner {
+    symbols: RefCell<HashSet<Rc<str>>>,
+    sweep_at: Cell<usize>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self {
+            symbols: RefCell::new(HashSet::new()),
+            sweep_at: Cell::new(MIN_SWEEP_AT),
+        }
+    }
+
+    pub fn intern(&self, text: &str) -> Rc<str> {
+        let mut symbols = self.symbols.borrow_mut();
+        if let Some(symbol) = symbols.get(text) {
+            return Rc::clone(symbol);
+        }
+        let symbol: Rc<str> = Rc::from(text);
+        symbols.insert(Rc::clone(&symbol));
+        symbol
+    }
+
+    // Forgets the symbols nothing else holds any more, so that strings a
+    // script made and dropped don't live as long as the session. Only sweeps
+    // once there are twice as many symbols as the last sweep left.
+    pub fn sweep(&self) {
+        let mut symbols = self.symbols.borrow_mut();
+        if symbols.len() < self.sweep_at.get() {
+            return;
+        }
+        symbols.retain(|symbol| Rc::strong_count(symbol) > 1);
+        self.sweep_at.set((2 * symbols.len()).max(MIN_SWEEP_AT));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Lux;
+    use crate::object::Object;
+
+    #[test]
+    fn equal_text_shares_one_allocation() {
+        let interner = Interner::new();
+        let a = interner.intern("name");
+        let b = interner.intern(&String::from("name"));
+        assert!(Rc::ptr_eq(&a, &b));
+        assert!(!Rc::ptr_eq(&a, &interner.intern("other")));
+    }
+
+    #[test]
+    fn strings_are_shared_across_runs_and_with_runtime_strings() {
+        let mut lux = Lux::new();
+        lux.run("var a = \"name\";").unwrap();
+        lux.run("var b = \"name\"; var c = \"na\" + \"me\";").unwrap();
+        let string = |name| match lux.global(name) {
+            Object::Str(s) => s,
+            other => panic!("expected a string, got {:?}", other),
+        };
+        assert!(Rc::ptr_eq(&string("a"), &string("b")));
+        assert!(Rc::ptr_eq(&string("a"), &string("c")));
+    }
+
+    #[test]
+    fn dropped_symbols_are_swept() {
+        let interner = Interner::new();
+        let kept = interner.intern("kept");
+        for i in 0..MIN_SWEEP_AT {
+            interner.intern(&i.to_string());
+        }
+        interner.sweep();
+        assert_eq!(interner.symbols.borrow().len(), 1);
+        assert!(Rc::ptr_eq(&kept, &interner.intern("kept")));
+    }
+}
@@ -1,127 +1,617 @@
 use crate::environment::*;
 use crate::error::*;
+use crate::exec_signal::*;
 use crate::expr::*;
 use crate::lox_function::*;
+use crate::lox_class::*;
+use crate::lox_instance::*;
+use crate::lox_list::*;
+use crate::lox_map::*;
 use crate::object::*;
 use crate::stmt::*;
 use crate::token::Token;
 use crate::token_type::*;
 use crate::native_functions::*;
 use crate::callable::*;
+use crate::call_frame::*;
+use crate::gc::*;
+use crate::interner::Interner;
+use crate::vm::Upvalue;
+use crate::budget::*;
+use crate::span::Span;
 
 
 use std::collections::HashMap;
 use std::rc::Rc;
 use std::ops::Deref;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::result;
 
 
 pub struct Interpreter {
     pub globals: Rc<RefCell<Environment>>,
     environment: RefCell<Rc<RefCell<Environment>>>,
-    locals: RefCell<HashMap<Rc<Expr>, usize>>
+    locals: RefCell<HashMap<Rc<Expr>, Binding>>,
+    frames: RefCell<Vec<CallFrame>>,
+    max_call_depth: Cell<usize>,
+    // Bytes of native stack a run may use, measured from where it started.
+    stack_limit: Cell<usize>,
+    stack_base: Cell<usize>,
+    pub(crate) heap: Heap,
+    symbols: Interner,
+    budget: Budget,
+}
+
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1000;
+
+// Each call the tree walker makes takes a few kilobytes of native stack, or
+// tens of them in debug builds, so recursion is also cut off once this much
+// is in use. It leaves room to spare on the 2 MiB stack a new thread gets by
+// default; hosts running the interpreter on a bigger one can raise it.
+pub const DEFAULT_STACK_LIMIT: usize = 1024 * 1024;
+
+// Roughly what a map entry costs, counting its key, value and index.
+const MAP_ENTRY_SIZE: usize = 2 * std::mem::size_of::<MapKey>() + std::mem::size_of::<Object>();
+
+// The address of a local, which says how deep the native stack is. Stacks
+// grow downwards on every platform Lox runs on.
+#[inline(never)]
+fn stack_position() -> usize {
+    let marker = 0u8;
+    std::hint::black_box(&marker) as *const u8 as usize
 }
 
 
 impl Interpreter {
     pub fn new() -> Self {
         let globals = Rc::new(RefCell::new(Environment::new()));
-        globals.borrow_mut().define(&"clock".to_string(), Object::Func(Callable{
-            func: Rc::new(Nativeclock{}),
+        globals.borrow_mut().define("clock".into(), Object::Func(Callable{
+            func: Rc::new(Nativeclock { name: "clock".into() }),
             arity: 0,
         }));
         Self {
             globals: Rc::clone(&globals),
             environment: RefCell::new(Rc::clone(&globals)),
-            locals: RefCell::new(HashMap::new())
+            locals: RefCell::new(HashMap::new()),
+            frames: RefCell::new(Vec::new()),
+            max_call_depth: Cell::new(DEFAULT_MAX_CALL_DEPTH),
+            stack_limit: Cell::new(DEFAULT_STACK_LIMIT),
+            stack_base: Cell::new(0),
+            heap: Heap::new(),
+            symbols: Interner::new(),
+            budget: Budget::new(),
         }
     }
 
     fn evaluate(&self,  expr: Rc<Expr>) -> Result<Object, LoxResult> {
+        self.step(|| Some(expr.span()))?;
         expr.accept( expr.clone(), self)
     }
 
-    fn execute(&self, stmt:Rc<Stmt>) -> Result<(), LoxResult> {
+    fn execute(&self, stmt:Rc<Stmt>) -> Result<(), ExecSignal> {
+        self.step(|| stmt.span())?;
+        self.collect_garbage();
         stmt.accept(stmt.clone(),self)
     }
 
     pub fn exceute_block(&self, statements: &Rc<Vec<Rc<Stmt>>>, environment: Environment) 
-    -> Result<(), LoxResult> {
+    -> Result<(), ExecSignal> {
         let previous = self.environment
         .replace(Rc::new(RefCell::new(environment)));
         let result = statements
         .iter()
         .try_for_each(|statement| self.execute(statement.clone()));
           
-        self.environment.replace(previous);
+        let environment = self.environment.replace(previous);
+        // Gone now, unless a closure captured it.
+        if Rc::strong_count(&environment) == 1 {
+            self.budget.release(Interpreter::environment_size(&environment.borrow()));
+        }
 
         result
         
     }
 
-    fn is_truthy(&self, obj: &Object) -> bool {
+    // Calls stop at this depth, or sooner if the stack limit is reached
+    // first, so a depth the stack can't hold is safe to ask for.
+    pub fn set_max_call_depth(&self, depth: usize) {
+        self.max_call_depth.set(depth);
+    }
+
+    // Limits how many bytes of native stack one run may use. It must be
+    // well below the size of the stack the interpreter runs on.
+    pub fn set_stack_limit(&self, bytes: usize) {
+        self.stack_limit.set(bytes);
+    }
+
+    // Limits how many statements and expressions one run may evaluate.
+    pub fn set_max_steps(&self, steps: Option<u64>) {
+        self.budget.set_max_steps(steps);
+    }
+
+    // Limits how long one run may take.
+    pub fn set_time_limit(&self, limit: Option<std::time::Duration>) {
+        self.budget.set_time_limit(limit);
+    }
+
+    // Limits roughly how many bytes one run may allocate.
+    pub fn set_max_memory(&self, bytes: Option<usize>) {
+        self.budget.set_max_memory(bytes);
+    }
+
+    // Gives a new run the whole budget, and measures its stack use from
+    // here.
+    pub(crate) fn start_run(&self) {
+        self.budget.start();
+        self.stack_base.set(stack_position());
+    }
+
+    // Counts a step against the budget; `span` says where the script was if
+    // that exhausts it.
+    pub(crate) fn step(&self, span: impl FnOnce() -> Option<Span>) -> Result<(), LoxResult> {
+        self.budget
+            .step()
+            .map_err(|resource| LoxResult::resource_limit(resource, span(), &self.budget.message(resource)))
+    }
+
+    // Runs `call` with a frame for it on the call stack, and gives runtime
+    // errors raised inside it a backtrace of the stack as it was then.
+    pub(crate) fn call_frame(
+        &self,
+        function: Rc<str>,
+        call_site: &Token,
+        call: impl FnOnce() -> Result<Object, LoxResult>,
+    ) -> Result<Object, LoxResult> {
+        self.push_frame(function, call_site)?;
+        let result = call().map_err(|error| self.with_backtrace(error));
+        self.pop_frame();
+        result
+    }
+
+    // Records a call on the call stack, refusing to recurse any further
+    // rather than overflow the native stack.
+    pub(crate) fn push_frame(&self, function: Rc<str>, call_site: &Token) -> Result<(), LoxResult> {
+        let stack_used = self.stack_base.get().saturating_sub(stack_position());
+        if self.frames.borrow().len() >= self.max_call_depth.get() || stack_used > self.stack_limit.get() {
+            return Err(LoxResult::runtime_error(call_site, "Stack overflow."));
+        }
+        self.frames.borrow_mut().push(CallFrame { function, call_site: call_site.span });
+        Ok(())
+    }
+
+    pub(crate) fn pop_frame(&self) {
+        self.frames.borrow_mut().pop();
+    }
+
+    // Gives a runtime error that has no backtrace yet the call stack as it is
+    // now.
+    pub(crate) fn with_backtrace(&self, mut error: LoxResult) -> LoxResult {
+        if let LoxResult::RuntimeError { backtrace, .. } = &mut error
+            && backtrace.is_empty()
+        {
+            *backtrace = self.frames.borrow().iter().rev().cloned().collect();
+        }
+        error
+    }
+
+    pub(crate) fn check_arity(&self, arity: usize, arguments: usize, paren: &Token) -> Result<(), LoxResult> {
+        if arguments != arity {
+            return Err(LoxResult::runtime_error(
+                paren,
+                &format!("Expected {} arguments but got {}", arity, arguments)));
+        }
+        Ok(())
+    }
+
+    // Called for each environment a closure captures, so that the collector
+    // can free it once it is unreachable. The globals always are reachable.
+    pub(crate) fn track(&self, environment: &Rc<RefCell<Environment>>) {
+        if !Rc::ptr_eq(environment, &self.globals) {
+            self.heap.track(environment);
+        }
+    }
+
+    // The same, for the variables the VM's closures capture.
+    pub(crate) fn track_upvalue(&self, upvalue: &Rc<RefCell<Upvalue>>) {
+        self.heap.track_upvalue(upvalue);
+    }
+
+    // Frees the captured containers nothing can reach any more, if enough
+    // have been tracked since the last time. Both backends call it before
+    // each statement or loop iteration and call, and when a run ends.
+    pub(crate) fn collect_garbage(&self) {
+        if self.heap.should_collect() {
+            self.heap.collect();
+        }
+        self.symbols.sweep();
+    }
+
+    // Names and strings, shared by every run in the session.
+    pub(crate) fn symbols(&self) -> &Interner {
+        &self.symbols
+    }
+
+    // Counts `bytes` of new values against the budget.
+    pub(crate) fn allocate(&self, bytes: usize, span: Option<Span>) -> Result<(), LoxResult> {
+        self.budget
+            .allocate(bytes)
+            .map_err(|resource| LoxResult::resource_limit(resource, span, &self.budget.message(resource)))
+    }
+
+    pub(crate) fn allocate_environment(&self) -> Result<(), LoxResult> {
+        self.allocate(std::mem::size_of::<Environment>(), None)
+    }
+
+    // What an environment was counted as, by `allocate_environment` and then
+    // a slot at a time as its locals were defined.
+    fn environment_size(environment: &Environment) -> usize {
+        std::mem::size_of::<Environment>() + environment.slot_count() * std::mem::size_of::<Object>()
+    }
+
+    // The values below are created with their size counted against the
+    // budget; `span` is where the script made them.
+
+    pub(crate) fn new_string(&self, value: String, span: Option<Span>) -> Result<Object, LoxResult> {
+        self.allocate(value.len(), span)?;
+        Ok(Object::Str(self.symbols.intern(&value)))
+    }
+
+    pub(crate) fn new_list(&self, elements: Vec<Object>, span: Option<Span>) -> Result<Object, LoxResult> {
+        self.allocate(elements.len() * std::mem::size_of::<Object>(), span)?;
+        Ok(Object::List(Rc::new(RefCell::new(elements))))
+    }
+
+    pub(crate) fn new_map(&self, map: LoxMap, span: Option<Span>) -> Result<Object, LoxResult> {
+        self.allocate(map.len() * MAP_ENTRY_SIZE, span)?;
+        Ok(Object::Map(Rc::new(RefCell::new(map))))
+    }
+
+    pub(crate) fn is_truthy(&self, obj: &Object) -> bool {
         !matches!(obj, Object::Nil | Object::Bool(false))
     }
 
-    pub fn interpret(&mut self, statements: &[Rc<Stmt>]) -> bool {
-        let mut had_error = true;
-        for statement in statements {
-            if let Err(_e) = self.execute(statement.clone()) {
-                had_error = false;
-                break;
+    pub(crate) fn binding(&self, expr: &Rc<Expr>) -> Option<Binding> {
+        self.locals.borrow().get(expr).copied()
+    }
+
+    pub(crate) fn environment(&self) -> Rc<RefCell<Environment>> {
+        Rc::clone(&self.environment.borrow())
+    }
+
+    // Installs `environment`, returning the one it replaces.
+    pub(crate) fn replace_environment(&self, environment: Rc<RefCell<Environment>>) -> Rc<RefCell<Environment>> {
+        self.environment.replace(environment)
+    }
+
+    // The operations below are shared by the tree walker and the bytecode VM,
+    // which must behave identically.
+
+    pub(crate) fn call_value(&self, callee: Object, arguments: Vec<Object>, paren: &Token) -> Result<Object, LoxResult> {
+        match callee {
+            Object::Func(function) => {
+                self.check_arity(function.func.arity(), arguments.len(), paren)?;
+                self.call_frame(function.func.name(), paren, || {
+                    function.func.call(self, arguments)
+                })
+            }
+            Object::Class(klass) => {
+                self.check_arity(klass.arity(), arguments.len(), paren)?;
+                self.allocate(std::mem::size_of::<LoxInstance>(), Some(paren.span))?;
+                self.call_frame(klass.name(), paren, || {
+                    klass.instantiate(self, arguments, Rc::clone(&klass))
+                })
+            }
+            _ => Err(LoxResult::runtime_error(
+                paren, "Can only call functions and classes"))
+        }
+    }
+
+    pub(crate) fn get_property(&self, object: Object, name: &Token) -> Result<Object, LoxResult> {
+        match object {
+            Object::Instance(instance) => LoxInstance::get(&instance, name, self),
+            Object::List(list) => ListMethod::lookup(&list, name),
+            Object::Map(map) => MapMethod::lookup(&map, name),
+            _ => Err(LoxResult::runtime_error(
+                name, "Only instances have properties.")),
+        }
+    }
+
+    pub(crate) fn set_property(&self, object: Object, name: &Token, value: Object) -> Result<Object, LoxResult> {
+        if let Object::Instance(instance) = object {
+            if !instance.has_field(name) {
+                self.allocate(std::mem::size_of::<(Rc<str>, Object)>(), Some(name.span))?;
+            }
+            instance.set(name, value.clone());
+            Ok(value)
+        } else {
+            Err(LoxResult::runtime_error(
+                name, "Only instances have fields."))
+        }
+    }
+
+    pub(crate) fn get_index(&self, object: Object, index: Object, bracket: &Token) -> Result<Object, LoxResult> {
+        match object {
+            Object::List(list) => {
+                let list = list.borrow();
+                let i = list_index(bracket, &index, list.len())?;
+                Ok(list[i].clone())
+            }
+            Object::Map(map) => {
+                let key = MapKey::from_object(bracket, &index)?;
+                map.borrow().get(&key).cloned().ok_or_else(|| {
+                    LoxResult::runtime_error(bracket, &format!("Undefined key {}.", key))
+                })
             }
+            _ => Err(LoxResult::runtime_error(
+                bracket, "Only lists and maps can be indexed.")),
         }
-        had_error
     }
 
-    pub fn resolve(&self, expr:Rc<Expr>, depth: usize) {
-        self.locals.borrow_mut().insert(expr, depth);
+    pub(crate) fn set_index(&self, object: Object, index: Object, value: Object, bracket: &Token) -> Result<Object, LoxResult> {
+        match object {
+            Object::List(list) => {
+                let mut list = list.borrow_mut();
+                let i = list_index(bracket, &index, list.len())?;
+                list[i] = value.clone();
+                Ok(value)
+            }
+            Object::Map(map) => {
+                let key = MapKey::from_object(bracket, &index)?;
+                if !map.borrow().contains(&key) {
+                    self.allocate(MAP_ENTRY_SIZE, Some(bracket.span))?;
+                }
+                map.borrow_mut().insert(key, value.clone());
+                Ok(value)
+            }
+            _ => Err(LoxResult::runtime_error(
+                bracket, "Only lists and maps can be indexed.")),
+        }
+    }
+
+    // Looks up `method` on `superclass`, binding it to `object`.
+    pub(crate) fn super_method(&self, superclass: Object, object: Object, method: &Token) -> Result<Object, LoxResult> {
+        if let Object::Class(superclass) = superclass
+            && let Some(method) = superclass.find_method(&method.lexeme) {
+            let bound = method.bind(&object, self);
+            let arity = bound.arity();
+            return Ok(Object::Func(Callable { func: Rc::new(bound), arity }));
+        }
+        Err(LoxResult::runtime_error(
+            method,
+            &format!("Undefined property '{}'.", method.as_string())))
+    }
+
+    pub(crate) fn unary(&self, operator: &Token, right: Object) -> Result<Object, LoxResult> {
+        match operator.ttype {
+            TokenType::Minus => {
+                if let Object::Num(n) = right {
+                    Ok(Object::Num(-n))
+                } else {
+                    Ok(Object::Nil)
+                }
+            }
+            TokenType::Bang => Ok(Object::Bool(!self.is_truthy(&right))),
+
+            _ => Err(LoxResult::runtime_error(operator, "Unreachable code.")),
+        }
+    }
+
+    pub(crate) fn binary(&self, operator: &Token, left: Object, right: Object) -> Result<Object, LoxResult> {
+        let op = operator.token_type();
+
+        let result = match (left, right) {
+            (Object::Num(l), Object::Num(r)) => match op {
+                TokenType::Plus => Object::Num(l + r),
+                TokenType::Minus => Object::Num(l - r),
+                TokenType::Star => Object::Num(l * r),
+                TokenType::Slash => Object::Num(l / r),
+                TokenType::Greater => Object::Bool(l > r),
+                TokenType::GreaterEqual => Object::Bool(l >= r),
+                TokenType::Less => Object::Bool(l < r),
+                TokenType::LessEqual => Object::Bool(l <= r),
+                TokenType::Equal => Object::Bool(l == r),
+                TokenType::BangEqual => Object::Bool(l != r),
+                _ => {
+                    return Err(LoxResult::runtime_error(
+                        operator,
+                        "Unknown binary operator.",
+                    ));
+                }
+            },
+            (Object::Num(l), Object::Str(r)) => match op {
+                TokenType::Plus => self.new_string(format!("{}{}", l, r), Some(operator.span))?,
+                _ => Object::ArithmeticError,
+            },
+            (Object::Str(l), Object::Num(r)) => match op {
+                TokenType::Plus => self.new_string(format!("{}{}", l, r), Some(operator.span))?,
+                _ => Object::ArithmeticError,
+            },
+            (Object::Str(l), Object::Str(r)) => match op {
+                // Checked before building it, as the result could be huge.
+                TokenType::Plus => {
+                    self.allocate(l.len() + r.len(), Some(operator.span))?;
+                    Object::Str(self.symbols.intern(&format!("{}{}", l, r)))
+                }
+                TokenType::Equal => Object::Bool(l == r),
+                TokenType::BangEqual => Object::Bool(l != r),
+                _ => Object::ArithmeticError,
+            },
+            (Object::Bool(l), Object::Bool(r)) => match op {
+                TokenType::Equal => Object::Bool(l == r),
+                TokenType::BangEqual => Object::Bool(l != r),
+                _ => Object::ArithmeticError,
+            },
+            (Object::Nil, Object::Nil) => match op {
+                TokenType::Equal => Object::Bool(true),
+                TokenType::BangEqual => Object::Bool(false),
+                _ => Object::ArithmeticError,
+            },
+            (Object::Nil, _) => match op {
+                TokenType::Equal => Object::Bool(false),
+                TokenType::BangEqual => Object::Bool(true),
+                _ => Object::ArithmeticError,
+            },
+            (l, r) if op == TokenType::Equal => Object::Bool(l == r),
+            (l, r) if op == TokenType::BangEqual => Object::Bool(l != r),
+            _ => {
+                return Err(LoxResult::runtime_error(
+                    operator,
+                    "Operands must be two numbers or two strings.",
+                ));
+            }
+        };
+
+        if result == Object::ArithmeticError {
+            Err(LoxResult::runtime_error(
+                operator,
+                "Operands must be numbers.",
+            ))
+        } else {
+            Ok(result)
+        }
+    }
+
+
+    pub(crate) fn superclass(&self, value: Object, token: &Token) -> Result<Rc<LoxClass>, LoxResult> {
+        match value {
+            Object::Class(superclass) => Ok(superclass),
+            _ => Err(LoxResult::runtime_error(token, "Superclass must be a class.")),
+        }
+    }
+
+    // Creates and defines a class whose methods close over an environment
+    // holding `super`, when there is a superclass.
+    pub(crate) fn declare_class(
+        &self,
+        name: &Token,
+        superclass: Option<Rc<LoxClass>>,
+        methods: impl FnOnce(&Rc<RefCell<Environment>>) -> HashMap<Rc<str>, Rc<LoxFunction>>,
+    ) -> Result<(), LoxResult> {
+        let enclosing = if let Some(superclass) = &superclass {
+            self.allocate_environment()?;
+            let mut e = Environment::new_with_enclosing(self.environment.borrow().clone());
+            e.push(Object::Class(Rc::clone(superclass)));
+            Some(self.environment.replace(Rc::new(RefCell::new(e))))
+        } else {
+            None
+        };
+
+        let closure = self.environment();
+        self.track(&closure);
+        let methods = methods(&closure);
+        let klass = LoxClass::new(Rc::clone(&name.lexeme), superclass, methods);
+
+        if let Some(previous) = enclosing {
+            self.environment.replace(previous);
+        }
+
+        // Defined only now, as nothing can refer to the class before its
+        // methods are called.
+        self.define(name, Object::Class(Rc::new(klass)))
+    }
+
+    pub fn interpret(&self, statements: &[Rc<Stmt>]) -> Result<(), LoxResult> {
+        self.start_run();
+        let result = statements
+            .iter()
+            .try_for_each(|statement| self.execute(statement.clone()))
+            .map_err(ExecSignal::into_error);
+        self.collect_garbage();
+        result
+    }
+
+    pub fn resolve(&self, expr:Rc<Expr>, depth: usize, slot: usize) {
+        self.locals.borrow_mut().insert(expr, Binding { depth, slot });
     }
 
     fn look_up_variable(&self, name:&Token, expr:Rc<Expr>) -> Result<Object, LoxResult> {
-        if let Some(distance) = self.locals.borrow().get(&expr) {
-            self.environment.borrow().borrow().get_at(distance.clone(), &name.as_string())
+        if let Some(binding) = self.locals.borrow().get(&expr) {
+            self.environment.borrow().borrow().get_at(binding.depth, binding.slot)
         } else {
             self.globals.borrow().get(name)
         }
         
     }
+
+    // Globals are defined by name; anywhere else the value takes the next
+    // slot, which is the one the resolver gave it.
+    pub(crate) fn define(&self, name: &Token, value: Object) -> Result<(), LoxResult> {
+        let environment = self.environment.borrow();
+        if Rc::ptr_eq(&environment, &self.globals) {
+            self.define_global(name, value)?;
+        } else {
+            self.allocate(std::mem::size_of::<Object>(), Some(name.span))?;
+            environment.borrow_mut().push(value);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn define_global(&self, name: &Token, value: Object) -> Result<(), LoxResult> {
+        self.allocate(std::mem::size_of::<(Rc<str>, Object)>(), Some(name.span))?;
+        self.globals.borrow_mut().define(Rc::clone(&name.lexeme), value);
+        Ok(())
+    }
 }
 
-impl StmtVisitor<()> for Interpreter {
-    fn visit_return_stmt(&self, _:Rc<Stmt>, stmt: &ReturnStmt) -> Result<(), LoxResult> {
+impl StmtVisitor<(), ExecSignal> for Interpreter {
+    fn visit_return_stmt(&self, _:Rc<Stmt>, stmt: &ReturnStmt) -> Result<(), ExecSignal> {
         if let Some(value) = stmt.value.clone() {
-            Err(LoxResult::return_value(self.evaluate(value)?))
+            Err(ExecSignal::Return(self.evaluate(value)?))
         } else{
-            Err(LoxResult::return_value(Object::Nil))
+            Err(ExecSignal::Return(Object::Nil))
         }
     }
-    fn visit_function_stmt(&self, _:Rc<Stmt>, stmt: &FunctionStmt) -> Result<(), LoxResult> {
-        let function = LoxFunction::new(
-            &Rc::new(stmt), 
-            &self.environment.borrow().deref()
-        );
-        self.environment
-            .borrow()
-            .borrow_mut()
-            .define(&stmt.name.as_string(), 
-            Object::Func(Callable { func: Rc::new(function), arity: stmt.params.len() }));
+    fn visit_function_stmt(&self, _:Rc<Stmt>, stmt: &FunctionStmt) -> Result<(), ExecSignal> {
+        let closure = self.environment();
+        self.track(&closure);
+        let function = LoxFunction::new(stmt, &closure, false);
+        self.define(
+            &stmt.name,
+            Object::Func(Callable { func: Rc::new(function), arity: stmt.params.len() }))?;
         Ok(())
     }
-    fn visit_break_stmt(&self, _:Rc<Stmt>, _: &BreakStmt) -> Result<(), LoxResult> {
-        Err(LoxResult::Break)
+    fn visit_class_stmt(&self, _:Rc<Stmt>, stmt: &ClassStmt) -> Result<(), ExecSignal> {
+        let superclass = match &stmt.superclass {
+            Some(superclass) => {
+                let value = self.evaluate(Rc::new(Expr::Variable(Rc::clone(superclass))))?;
+                Some(self.superclass(value, &superclass.name)?)
+            }
+            None => None,
+        };
+        self.declare_class(&stmt.name, superclass, |closure| {
+            stmt.methods
+                .iter()
+                .map(|method| {
+                    let is_initializer = &*method.name.lexeme == "init";
+                    let function = LoxFunction::new(method, closure, is_initializer);
+                    (Rc::clone(&method.name.lexeme), Rc::new(function))
+                })
+                .collect()
+        })?;
+        Ok(())
     }
 
-    fn visit_while_stmt(&self, _:Rc<Stmt>, stmt: &WhileStmt) -> Result<(), LoxResult> { 
+    fn visit_break_stmt(&self, _:Rc<Stmt>, _: &BreakStmt) -> Result<(), ExecSignal> {
+        Err(ExecSignal::Break)
+    }
+
+    fn visit_continue_stmt(&self, _:Rc<Stmt>, _: &ContinueStmt) -> Result<(), ExecSignal> {
+        Err(ExecSignal::Continue)
+    }
+
+    fn visit_while_stmt(&self, _:Rc<Stmt>, stmt: &WhileStmt) -> Result<(), ExecSignal> { 
 
         let mut condition = self.evaluate(stmt.condition.clone())?;
         while self.is_truthy(&condition) {
              match self.execute(stmt.body.clone()) {
-                Err(LoxResult::Break) => break,
+                Err(ExecSignal::Break) => break,
+                Err(ExecSignal::Continue) => {}
                 Err(e) => return Err(e),
                 Ok(_) => {}
             }
+            if let Some(increment) = &stmt.increment {
+                self.evaluate(increment.clone())?;
+            }
             condition = self.evaluate(stmt.condition.clone())?;
         }    
     
@@ -129,7 +619,7 @@ impl StmtVisitor<()> for Interpreter {
         Ok(())
     }
 
-    fn visit_if_stmt(&self, _:Rc<Stmt>, stmt: &IfStmt) -> Result<(), LoxResult> {
+    fn visit_if_stmt(&self, _:Rc<Stmt>, stmt: &IfStmt) -> Result<(), ExecSignal> {
         let condition = self.evaluate(stmt.condition.clone())?;
 
         if self.is_truthy(&condition) {
@@ -141,34 +631,32 @@ impl StmtVisitor<()> for Interpreter {
         }       
     }  
 
-    fn visit_block_stmt(&self, _:Rc<Stmt>, stmt: &BlockStmt) -> Result<(), LoxResult> {
+    fn visit_block_stmt(&self, _:Rc<Stmt>, stmt: &BlockStmt) -> Result<(), ExecSignal> {
+        self.allocate_environment()?;
         let e = Environment::new_with_enclosing(self.environment.borrow().clone());
         self.exceute_block(&stmt.statements, e)           
     
     }
 
-    fn visit_expression_stmt(&self, _:Rc<Stmt>, stmt: &ExpressionStmt) -> Result<(), LoxResult> {
+    fn visit_expression_stmt(&self, _:Rc<Stmt>, stmt: &ExpressionStmt) -> Result<(), ExecSignal> {
         self.evaluate(stmt.expression.clone())?;
         Ok(())
     }
 
-    fn visit_print_stmt(&self, _:Rc<Stmt>, stmt: &PrintStmt) -> Result<(), LoxResult> {
+    fn visit_print_stmt(&self, _:Rc<Stmt>, stmt: &PrintStmt) -> Result<(), ExecSignal> {
         let value = self.evaluate(stmt.expression.clone())?;
         println!("{value}");
         Ok(())
     }
 
-    fn visit_var_stmt(&self, _:Rc<Stmt>, stmt: &VarStmt) -> Result<(), LoxResult> {
+    fn visit_var_stmt(&self, _:Rc<Stmt>, stmt: &VarStmt) -> Result<(), ExecSignal> {
         let value = if let Some(initializer) = stmt.initializer.clone() {
             self.evaluate(initializer)?
         } else {
             Object::Nil
         };
 
-        self.environment
-            .borrow()
-            .borrow_mut()
-            .define(&stmt.name.as_string(), value);
+        self.define(&stmt.name, value)?;
         Ok(())
     }
 }
@@ -180,20 +668,81 @@ impl ExprVisitor<Object> for Interpreter {
         for argument in &expr.arguments{
             arguments.push(self.evaluate(argument.clone())?);
         }
+        self.call_value(callee, arguments, &expr.paren)
+    }
 
-        if let Object::Func(function) = callee {
-            if arguments.len() != function.func.arity() {
-                return Err(LoxResult::runtime_error(
-                    &expr.paren, 
-                    &format!("Expected {} arguments but got {}", 
-                function.func.arity(), arguments.len())));
-            }
-            function.func.call(self, arguments)
-        } else {
-            Err(LoxResult::runtime_error(
-                &expr.paren, "Can only call functions and classes"))
+    fn visit_get_expr(&self, _:Rc<Expr>, expr: &GetExpr) -> Result<Object, LoxResult> {
+        let object = self.evaluate(expr.object.clone())?;
+        self.get_property(object, &expr.name)
+    }
+
+    fn visit_index_expr(&self, _:Rc<Expr>, expr: &IndexExpr) -> Result<Object, LoxResult> {
+        let object = self.evaluate(expr.object.clone())?;
+        let index = self.evaluate(expr.index.clone())?;
+        self.get_index(object, index, &expr.bracket)
+    }
+
+    fn visit_indexset_expr(&self, _:Rc<Expr>, expr: &IndexSetExpr) -> Result<Object, LoxResult> {
+        let object = self.evaluate(expr.object.clone())?;
+        let index = self.evaluate(expr.index.clone())?;
+        let value = self.evaluate(expr.value.clone())?;
+        self.set_index(object, index, value, &expr.bracket)
+    }
+
+    fn visit_interpolation_expr(&self, _:Rc<Expr>, expr: &InterpolationExpr) -> Result<Object, LoxResult> {
+        let mut result = String::new();
+        for part in &expr.parts {
+            result.push_str(&self.evaluate(part.clone())?.to_string());
         }
-        
+        self.new_string(result, Some(expr.token.span))
+    }
+
+    fn visit_lambda_expr(&self, _:Rc<Expr>, expr: &LambdaExpr) -> Result<Object, LoxResult> {
+        let closure = self.environment();
+        self.track(&closure);
+        let function = LoxFunction::lambda(expr, &closure);
+        Ok(Object::Func(Callable { func: Rc::new(function), arity: expr.params.len() }))
+    }
+
+    fn visit_list_expr(&self, _:Rc<Expr>, expr: &ListExpr) -> Result<Object, LoxResult> {
+        let mut elements = Vec::new();
+        for element in &expr.elements {
+            elements.push(self.evaluate(element.clone())?);
+        }
+        self.new_list(elements, Some(expr.bracket.span))
+    }
+
+    fn visit_map_expr(&self, _:Rc<Expr>, expr: &MapExpr) -> Result<Object, LoxResult> {
+        let mut map = LoxMap::new();
+        for (key, value) in expr.keys.iter().zip(expr.values.iter()) {
+            let key = MapKey::from_object(&expr.brace, &self.evaluate(key.clone())?)?;
+            map.insert(key, self.evaluate(value.clone())?);
+        }
+        self.new_map(map, Some(expr.brace.span))
+    }
+
+    fn visit_set_expr(&self, _:Rc<Expr>, expr: &SetExpr) -> Result<Object, LoxResult> {
+        let object = self.evaluate(expr.object.clone())?;
+        if !matches!(object, Object::Instance(_)) {
+            return Err(LoxResult::runtime_error(
+                &expr.name, "Only instances have fields."));
+        }
+        let value = self.evaluate(expr.value.clone())?;
+        self.set_property(object, &expr.name, value)
+    }
+
+    fn visit_super_expr(&self, wrapper:Rc<Expr>, expr: &SuperExpr) -> Result<Object, LoxResult> {
+        let Some(binding) = self.binding(&wrapper) else {
+            return Err(LoxResult::system_error("'super' was not resolved."));
+        };
+        // `this` is in the scope just inside the one holding `super`.
+        let superclass = self.environment.borrow().borrow().get_at(binding.depth, binding.slot)?;
+        let object = self.environment.borrow().borrow().get_at(binding.depth - 1, 0)?;
+        self.super_method(superclass, object, &expr.method)
+    }
+
+    fn visit_this_expr(&self, wrapper:Rc<Expr>, expr: &ThisExpr) -> Result<Object, LoxResult> {
+        self.look_up_variable(&expr.keyword, wrapper)
     }
 
     fn visit_literal_expr(&self,  _:Rc<Expr>, expr: &LiteralExpr) -> Result<Object, LoxResult> {
@@ -210,19 +759,7 @@ impl ExprVisitor<Object> for Interpreter {
 
     fn visit_unary_expr(&self, _:Rc<Expr>, expr: &UnaryExpr) -> Result<Object, LoxResult> {
         let right = self.evaluate(expr.right.clone())?;
-
-        match expr.operator.ttype {
-            TokenType::Minus => {
-                if let Object::Num(n) = right {
-                    Ok(Object::Num(-n))
-                } else {
-                    Ok(Object::Nil)
-                }
-            }
-            TokenType::Bang => Ok(Object::Bool(!self.is_truthy(&right))),
-
-            _ => Err(LoxResult::error(expr.operator.line, "Unreachable code.")),
-        }
+        self.unary(&expr.operator, right)
     }
 
     fn visit_logical_expr(&self,  _:Rc<Expr>, expr: &LogicalExpr) -> Result<Object, LoxResult> {
@@ -241,72 +778,7 @@ impl ExprVisitor<Object> for Interpreter {
     fn visit_binary_expr(&self, _:Rc<Expr>, expr: &BinaryExpr) -> Result<Object, LoxResult> {
         let left = self.evaluate(expr.left.clone())?; //expr.left.accept(self)?;
         let right = self.evaluate(expr.right.clone())?;
-        let op = expr.operator.token_type();
-
-        let result = match (left, right) {
-            (Object::Num(l), Object::Num(r)) => match op {
-                TokenType::Plus => Object::Num(l + r),
-                TokenType::Minus => Object::Num(l - r),
-                TokenType::Star => Object::Num(l * r),
-                TokenType::Slash => Object::Num(l / r),
-                TokenType::Greater => Object::Bool(l > r),
-                TokenType::GreaterEqual => Object::Bool(l >= r),
-                TokenType::Less => Object::Bool(l < r),
-                TokenType::LessEqual => Object::Bool(l <= r),
-                TokenType::Equal => Object::Bool(l == r),
-                TokenType::BangEqual => Object::Bool(l != r),
-                _ => {
-                    return Err(LoxResult::error(
-                        expr.operator.line,
-                        "Unknown binary operator.",
-                    ));
-                }
-            },
-            (Object::Num(l), Object::Str(r)) => match op {
-                TokenType::Plus => Object::Str(format!("{}{}", l, r)),
-                _ => Object::ArithmeticError,
-            },
-            (Object::Str(l), Object::Num(r)) => match op {
-                TokenType::Plus => Object::Str(format!("{}{}", l, r)),
-                _ => Object::ArithmeticError,
-            },
-            (Object::Str(l), Object::Str(r)) => match op {
-                TokenType::Plus => Object::Str(format!("{}{}", l, r)),
-                TokenType::Equal => Object::Bool(l == r),
-                TokenType::BangEqual => Object::Bool(l != r),
-                _ => Object::ArithmeticError,
-            },
-            (Object::Bool(l), Object::Bool(r)) => match op {
-                TokenType::Equal => Object::Bool(l == r),
-                TokenType::BangEqual => Object::Bool(l != r),
-                _ => Object::ArithmeticError,
-            },
-            (Object::Nil, Object::Nil) => match op {
-                TokenType::Equal => Object::Bool(true),
-                TokenType::BangEqual => Object::Bool(false),
-                _ => Object::ArithmeticError,
-            },
-            (Object::Nil, _) => match op {
-                TokenType::Equal => Object::Bool(false),
-                TokenType::BangEqual => Object::Bool(true),
-                _ => Object::ArithmeticError,
-            },
-            _ => {
-                return Err(LoxResult::runtime_error(
-                    &expr.operator,
-                    "Operands must be two numbers or two strings.",
-                ));
-            }
-        };
-
-        if result == Object::ArithmeticError {
-            Err(LoxResult::runtime_error(
-                &expr.operator,
-                "Operands must be numbers.",
-            ))
-        } else {
-            Ok(result)
-        }
+        self.binary(&expr.operator, left, right)
     }
 
     fn visit_variable_expr(&self, wrapper:Rc<Expr>, expr: &VariableExpr) -> Result<Object, LoxResult> {
@@ -316,11 +788,11 @@ impl ExprVisitor<Object> for Interpreter {
 
     fn visit_assign_expr(&self,  wrapper:Rc<Expr>, expr: &AssignExpr) -> Result<Object, LoxResult> {
         let value = self.evaluate(expr.value.clone())?;
-        if let Some(distance) = self.locals.borrow().get(&wrapper) {   
+        if let Some(binding) = self.locals.borrow().get(&wrapper) {   
         self.environment
             .borrow()
             .borrow_mut()            
-            .assign_at(*distance, &expr.name, value.clone())?;
+            .assign_at(binding.depth, binding.slot, value.clone())?;
         } else{
             self.globals.borrow_mut().assign(&expr.name, value.clone())?;
         }
@@ -556,7 +1028,7 @@ mod tests {
         };
         let result = interpreter.visit_binary_expr(&expr);
         assert!(result.is_ok());
-        assert_eq!(result.ok(), Some(Object::Str("hello, world".to_string())));
+        assert_eq!(result.ok(), Some(Object::Str("hello, world".into())));
     }
 
     #[test]
@@ -711,7 +1183,7 @@ mod tests {
         assert_eq!(interpreter.is_truthy(&Object::Bool(true)), true);
         assert_eq!(interpreter.is_truthy(&Object::Num(0.0)), true);
         assert_eq!(
-            interpreter.is_truthy(&Object::Str("hello".to_string())),
+            interpreter.is_truthy(&Object::Str("hello".into())),
             true
         );
     }
@@ -755,4 +1227,215 @@ mod tests {
         assert!(interpreter.visit_variable_expr(&var_expr).is_err())
     }
 }
-*/
\ No newline at end of file
+*/
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Lux;
+
+
+    #[test]
+    fn interpolation_stringifies_embedded_expressions() {
+        let mut lux = Lux::new();
+        lux.run(
+            "var name = \"Ann\";
+            var count = 2;
+            var items = [1, \"x\"];
+            var result = \"Hello ${name}, you have ${count + 1} items: ${items} ${nil}\";
+            var nested = \"a${\"b${1 + 1}c\"}d\";",
+        )
+        .unwrap();
+        assert_eq!(
+            lux.global("result"),
+            Object::Str("Hello Ann, you have 3 items: [1, \"x\"] nil".into())
+        );
+        assert_eq!(lux.global("nested"), Object::Str("ab2cd".into()));
+    }
+
+    #[test]
+    fn continue_runs_the_for_increment() {
+        let mut lux = Lux::new();
+        lux.run(
+            "var sum = 0;
+            for (var i = 0; i < 10; i = i + 1) {
+                if (i == 2) continue;
+                if (i == 5) continue;
+                sum = sum + i;
+            }
+            var count = 0;
+            var j = 0;
+            while (j < 5) {
+                j = j + 1;
+                if (j == 3) continue;
+                count = count + 1;
+            }",
+        )
+        .unwrap();
+        assert_eq!(lux.global("sum"), Object::Num(38.0));
+        assert_eq!(lux.global("count"), Object::Num(4.0));
+    }
+
+    #[test]
+    fn lambdas_close_over_their_environment() {
+        let mut lux = Lux::new();
+        lux.run(
+            "fun apply(f, x) { return f(x); }
+            fun adder(n) { return (x) => x + n; }
+            var add = fun (a, b) { return a + b; };
+            var sum = add(1, 2);
+            var applied = apply(adder(10), 5);
+            var noArgs = (() => \"hi\")();
+            fun (x) { sum = sum + x; }(100);",
+        )
+        .unwrap();
+        assert_eq!(lux.global("sum"), Object::Num(103.0));
+        assert_eq!(lux.global("applied"), Object::Num(15.0));
+        assert_eq!(lux.global("noArgs"), Object::Str("hi".into()));
+    }
+
+    #[test]
+    fn parenthesised_expressions_are_not_arrows() {
+        let mut lux = Lux::new();
+        lux.run("var a = 2; var b = (a) * 3;").unwrap();
+        assert_eq!(lux.global("b"), Object::Num(6.0));
+    }
+
+    #[test]
+    fn interpret_reports_only_real_errors() {
+        let mut lux = Lux::new();
+        lux.run(
+            "fun find(xs, x) {
+                for (var i = 0; i < xs.len(); i = i + 1) {
+                    if (xs[i] == x) return i;
+                }
+                return -1;
+            }
+            var found = find([5, 6, 7], 7);",
+        )
+        .unwrap();
+        assert_eq!(lux.global("found"), Object::Num(2.0));
+
+        let result = lux.run("var boom = -\"x\" + 1;");
+        let errors = result.unwrap_err();
+        assert!(matches!(errors.as_slice(), [LoxResult::RuntimeError { .. }]));
+        let error: &dyn std::error::Error = &errors[0];
+        assert!(error.to_string().contains("Operands must be"));
+    }
+
+    #[test]
+    fn runtime_errors_carry_a_backtrace() {
+        let mut lux = Lux::new();
+        let errors = lux
+            .run(
+                "fun inner(x) {
+                    return x + nil;
+                }
+                fun outer(x) {
+                    return inner(x);
+                }
+                outer(1);",
+            )
+            .unwrap_err();
+        let LoxResult::RuntimeError { backtrace, .. } = &errors[0] else {
+            panic!("expected a runtime error, got {:?}", errors);
+        };
+        let calls: Vec<(&str, usize)> = backtrace
+            .iter()
+            .map(|frame| (&*frame.function, frame.call_site.line))
+            .collect();
+        assert_eq!(calls, vec![("inner", 5), ("outer", 7)]);
+        assert!(lux.interpreter.frames.borrow().is_empty());
+    }
+
+    #[test]
+    fn unbounded_recursion_is_a_stack_overflow_error() {
+        let mut lux = Lux::new();
+        lux.interpreter.set_max_call_depth(50);
+        lux.run("fun depth(n) { if (n == 0) return 0; return 1 + depth(n - 1); }")
+            .unwrap();
+        lux.run("var ok = depth(49);").unwrap();
+        assert_eq!(lux.global("ok"), Object::Num(49.0));
+
+        let errors = lux.run("fun forever(n) { return forever(n + 1); } forever(0);").unwrap_err();
+        let LoxResult::RuntimeError { message, backtrace, .. } = &errors[0] else {
+            panic!("expected a runtime error, got {:?}", errors);
+        };
+        assert_eq!(message, "Stack overflow.");
+        assert_eq!(backtrace.len(), 50);
+
+        // The interpreter is still usable afterwards.
+        lux.run("var after = depth(3);").unwrap();
+        assert_eq!(lux.global("after"), Object::Num(3.0));
+    }
+
+    #[test]
+    fn recursion_stops_before_the_native_stack_runs_out() {
+        // Test threads only get a 2 MiB stack, far less than a million calls
+        // would need, so the stack limit has to stop this.
+        let mut lux = Lux::new();
+        lux.interpreter.set_max_call_depth(1_000_000);
+        let errors = lux
+            .run("fun forever(n) { return 1 + forever(n + 1); } forever(0);")
+            .unwrap_err();
+        let LoxResult::RuntimeError { message, backtrace, .. } = &errors[0] else {
+            panic!("expected a runtime error, got {:?}", errors);
+        };
+        assert_eq!(message, "Stack overflow.");
+        assert!(backtrace.len() < 1_000_000);
+
+        // The VM keeps its frames off the native stack, so only the depth
+        // limit applies.
+        let mut lux = Lux::new();
+        lux.use_vm = true;
+        lux.interpreter.set_max_call_depth(100_000);
+        lux.run("fun down(n) { if (n == 0) return 0; return 1 + down(n - 1); } var deep = down(99999);")
+            .unwrap();
+        assert_eq!(lux.global("deep"), Object::Num(99999.0));
+        let errors = lux.run("down(100000);").unwrap_err();
+        let LoxResult::RuntimeError { message, backtrace, .. } = &errors[0] else {
+            panic!("expected a runtime error, got {:?}", errors);
+        };
+        assert_eq!(message, "Stack overflow.");
+        assert_eq!(backtrace.len(), 100_000);
+    }
+
+    // Run with `cargo test --release -- --ignored --nocapture fib_benchmark`.
+    #[test]
+    #[ignore]
+    fn fib_benchmark() {
+        let mut lux = Lux::new();
+        let start = std::time::Instant::now();
+        lux.run(
+            "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+            var result = fib(25);",
+        )
+        .unwrap();
+        println!("fib(25) took {:?}", start.elapsed());
+        assert_eq!(lux.global("result"), Object::Num(75025.0));
+    }
+
+    // Reading strings and names dominates here, rather than arithmetic.
+    // Run with `cargo test --release -- --ignored --nocapture string_benchmark`.
+    #[test]
+    #[ignore]
+    fn string_benchmark() {
+        let mut lux = Lux::new();
+        let start = std::time::Instant::now();
+        lux.run(
+            "var text = \"The quick brown fox jumps over the lazy dog, again and again.\";
+            for (var i = 0; i < 6; i = i + 1) text = text + text;
+            var words = {\"fox\": text, \"dog\": text};
+            var count = 0;
+            for (var i = 0; i < 200000; i = i + 1) {
+                var copy = text;
+                var other = words[\"fox\"];
+                if (copy == other) count = count + 1;
+            }
+            var joined = \"\";
+            for (var i = 0; i < 2000; i = i + 1) joined = joined + \"x\";",
+        )
+        .unwrap();
+        println!("string benchmark took {:?}", start.elapsed());
+        assert_eq!(lux.global("count"), Object::Num(200000.0));
+    }
+}
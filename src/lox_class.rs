@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Display};
+use std::rc::Rc;
+
+use crate::callable::*;
+use crate::error::*;
+use crate::gc::*;
+use crate::interpreter::*;
+use crate::lox_function::*;
+use crate::lox_instance::*;
+use crate::object::*;
+
+pub struct LoxClass {
+    name: Rc<str>,
+    superclass: Option<Rc<LoxClass>>,
+    methods: HashMap<Rc<str>, Rc<LoxFunction>>,
+}
+
+impl LoxClass {
+    pub fn new(
+        name: Rc<str>,
+        superclass: Option<Rc<LoxClass>>,
+        methods: HashMap<Rc<str>, Rc<LoxFunction>>,
+    ) -> Self {
+        Self {
+            name,
+            superclass,
+            methods,
+        }
+    }
+
+    pub fn find_method(&self, name: &str) -> Option<Rc<LoxFunction>> {
+        if let Some(method) = self.methods.get(name) {
+            Some(Rc::clone(method))
+        } else if let Some(superclass) = &self.superclass {
+            superclass.find_method(name)
+        } else {
+            None
+        }
+    }
+
+    pub fn arity(&self) -> usize {
+        if let Some(initializer) = self.find_method("init") {
+            initializer.arity()
+        } else {
+            0
+        }
+    }
+
+    pub fn instantiate(
+        &self,
+        interpreter: &Interpreter,
+        arguments: Vec<Object>,
+        klass: Rc<LoxClass>,
+    ) -> Result<Object, LoxResult> {
+        let instance = Object::Instance(Rc::new(LoxInstance::new(klass)));
+        if let Some(initializer) = self.find_method("init") {
+            initializer.bind(&instance, interpreter).call(interpreter, arguments)?;
+        }
+        Ok(instance)
+    }
+
+    pub fn name(&self) -> Rc<str> {
+        Rc::clone(&self.name)
+    }
+}
+
+impl Trace for LoxClass {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(superclass) = &self.superclass {
+            tracer.object(&Object::Class(Rc::clone(superclass)));
+        }
+        for method in self.methods.values() {
+            tracer.method(method);
+        }
+    }
+}
+
+impl PartialEq for LoxClass {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Display for LoxClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+impl Debug for LoxClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<class {}>", self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Lux;
+
+
+    #[test]
+    fn can_call_methods_and_read_fields() {
+        let mut lux = Lux::new();
+        lux.run(
+            "class Counter {
+                init(start) { this.count = start; }
+                incr() { this.count = this.count + 1; return this; }
+            }
+            var c = Counter(5);
+            c.incr().incr();
+            var result = c.count;",
+        )
+        .unwrap();
+        assert_eq!(lux.global("result"), Object::Num(7.0));
+    }
+
+    #[test]
+    fn bound_methods_remember_this() {
+        let mut lux = Lux::new();
+        lux.run(
+            "class Person {
+                init(name) { this.name = name; }
+                greet() { return \"Hi \" + this.name; }
+            }
+            var greet = Person(\"Ann\").greet;
+            var result = greet();",
+        )
+        .unwrap();
+        assert_eq!(lux.global("result"), Object::Str("Hi Ann".into()));
+    }
+
+    #[test]
+    fn init_returns_the_instance() {
+        let mut lux = Lux::new();
+        lux.run(
+            "class Foo { init() { this.x = 1; return; } }
+            var foo = Foo();
+            var again = foo.init();",
+        )
+        .unwrap();
+        assert!(matches!(lux.global("again"), Object::Instance(_)));
+    }
+
+    #[test]
+    fn methods_are_inherited() {
+        let mut lux = Lux::new();
+        lux.run(
+            "class A { name() { return \"A\"; } twice() { return this.name() + this.name(); } }
+            class B < A {}
+            var result = B().twice();",
+        )
+        .unwrap();
+        assert_eq!(lux.global("result"), Object::Str("AA".into()));
+    }
+
+    #[test]
+    fn super_calls_the_superclass_method() {
+        let mut lux = Lux::new();
+        lux.run(
+            "class A {
+                init(x) { this.x = x; }
+                describe() { return \"A\" + this.x; }
+            }
+            class B < A {
+                init(x) { super.init(x * 2); }
+                describe() { return \"B/\" + super.describe(); }
+            }
+            class C < B {}
+            var result = C(4).describe();",
+        )
+        .unwrap();
+        assert_eq!(lux.global("result"), Object::Str("B/A8".into()));
+    }
+
+    #[test]
+    fn superclass_must_be_a_class() {
+        let mut lux = Lux::new();
+        let result = lux.run(
+            "var NotAClass = \"nope\";
+            var reached = false;
+            class Sub < NotAClass {}
+            reached = true;",
+        );
+        assert!(matches!(result.unwrap_err().as_slice(), [LoxResult::RuntimeError { .. }]));
+        assert_eq!(lux.global("reached"), Object::Bool(false));
+    }
+
+    #[test]
+    fn constructor_arity_is_checked() {
+        let mut lux = Lux::new();
+        let result = lux.run(
+            "class Point { init(x, y) { this.x = x; this.y = y; } }
+            var ok = true;
+            var p = Point(1);
+            ok = false;",
+        );
+        assert!(matches!(result.unwrap_err().as_slice(), [LoxResult::RuntimeError { .. }]));
+        assert_eq!(lux.global("ok"), Object::Bool(true));
+    }
+}
@@ -1,4 +1,3 @@
-
 use std::fmt::Display;
 use std::rc::Rc;
 use std::cell::RefCell;
@@ -9,56 +8,169 @@ use crate::token::*;
 use crate::callable::*;
 use crate::interpreter::*;
 use crate::error::*;
+use crate::exec_signal::*;
 use crate::stmt::*;
+use crate::expr::*;
+use crate::token_type::*;
+use crate::chunk::*;
+use crate::vm::*;
+use crate::gc::*;
+
+// What runs when the function is called: its statements in the environment
+// they close over for the tree walker, or their compiled code with the
+// variables it captured for the VM. A compiled method bound to an instance
+// holds it as `this`.
+#[derive(Clone)]
+enum FunctionBody {
+    Tree {
+        body: Rc<Vec<Rc<Stmt>>>,
+        closure: Rc<RefCell<Environment>>,
+    },
+    Bytecode {
+        chunk: Rc<Chunk>,
+        upvalues: Upvalues,
+        this: Option<Object>,
+    },
+}
 
 pub struct LoxFunction {
     name: Token,
     params: Rc<Vec<Token>>,
-    body: Rc<Vec<Rc<Stmt>>>,
-    closure: Rc<RefCell<Environment>>
+    body: FunctionBody,
+    is_initializer: bool,
 }
 
 impl LoxFunction {
-    pub fn new(declaration: &FunctionStmt, closure:&Rc<RefCell<Environment>>) -> Self {
+    pub fn new(declaration: &FunctionStmt, closure:&Rc<RefCell<Environment>>, is_initializer: bool) -> Self {
 
         Self { 
             name: declaration.name.dup(),
             params: Rc::clone(&declaration.params),
-            body : Rc::clone(&declaration.body),
-            closure: Rc::clone(closure)
+            body: FunctionBody::Tree {
+                body: Rc::clone(&declaration.body),
+                closure: Rc::clone(closure),
+            },
+            is_initializer,
          }
     }
+
+    pub fn lambda(expr: &LambdaExpr, closure: &Rc<RefCell<Environment>>) -> Self {
+        Self {
+            name: Token::new(TokenType::Identifier, "lambda".to_string(), None, expr.keyword.span.line),
+            params: Rc::clone(&expr.params),
+            body: FunctionBody::Tree {
+                body: Rc::clone(&expr.body),
+                closure: Rc::clone(closure),
+            },
+            is_initializer: false,
+        }
+    }
+
+    pub fn compiled(proto: &FunctionProto, upvalues: Upvalues, is_initializer: bool) -> Self {
+        Self {
+            name: proto.name.dup(),
+            params: Rc::clone(&proto.params),
+            body: FunctionBody::Bytecode {
+                chunk: Rc::clone(&proto.chunk),
+                upvalues,
+                this: None,
+            },
+            is_initializer,
+        }
+    }
+
+    // The code of a compiled function, what it captured, and the instance it
+    // is bound to, if any.
+    pub(crate) fn bytecode(&self) -> Option<(&Rc<Chunk>, &Upvalues, Option<&Object>)> {
+        match &self.body {
+            FunctionBody::Bytecode { chunk, upvalues, this } => Some((chunk, upvalues, this.as_ref())),
+            FunctionBody::Tree { .. } => None,
+        }
+    }
+
+    pub fn bind(&self, instance: &Object, interpreter: &Interpreter) -> Self {
+        let body = match &self.body {
+            FunctionBody::Tree { body, closure } => {
+                let mut environment = Environment::new_with_enclosing(Rc::clone(closure));
+                environment.push(instance.clone());
+                let closure = Rc::new(RefCell::new(environment));
+                interpreter.track(&closure);
+                FunctionBody::Tree { body: Rc::clone(body), closure }
+            }
+            FunctionBody::Bytecode { chunk, upvalues, .. } => FunctionBody::Bytecode {
+                chunk: Rc::clone(chunk),
+                upvalues: Rc::clone(upvalues),
+                this: Some(instance.clone()),
+            },
+        };
+        Self {
+            name: self.name.dup(),
+            params: Rc::clone(&self.params),
+            body,
+            is_initializer: self.is_initializer,
+        }
+    }
 }
 
 impl LoxCallable for LoxFunction {
     fn call(&self, interpreter:&Interpreter, arguments: Vec<Object>) -> Result<Object, LoxResult> {
-       
-        let mut e = Environment::new_with_enclosing(Rc::clone(&self.closure));
+        let (body, closure) = match &self.body {
+            FunctionBody::Tree { body, closure } => (body, closure),
+            // Compiled code returns `this` from initializers itself.
+            FunctionBody::Bytecode { chunk, upvalues, this } => {
+                let this = this.clone().unwrap_or(Object::Nil);
+                return Vm::new(interpreter).call(chunk, upvalues, this, arguments);
+            }
+        };
 
-        for (param, arg) in self.params.iter().zip(arguments.iter()) {
-            
-            e.define(&param.as_string(), arg.clone());
+        interpreter.allocate_environment()?;
+        interpreter.allocate(arguments.len() * std::mem::size_of::<Object>(), None)?;
+        let mut e = Environment::new_with_enclosing(Rc::clone(closure));
+
+        // Parameters take the first slots of the call's environment.
+        for arg in arguments {
+            e.push(arg);
         }
         
-        match interpreter.exceute_block(&self.body, e){
-            Err(LoxResult::RetrunValue{value}) => Ok(value),
-            Err(e) => Err(e),
-            Ok(_) => Ok(Object::Nil)
-
+        let value = match interpreter.exceute_block(body, e) {
+            Err(ExecSignal::Return(value)) => value,
+            Err(signal) => return Err(signal.into_error()),
+            Ok(_) => Object::Nil,
+        };
+        if self.is_initializer {
+            closure.borrow().get_at(0, 0)
+        } else {
+            Ok(value)
         }
-        //Ok(Object::Nil)   
-      
     }
 
     fn arity(&self) -> usize {       
             self.params.len()      
       }
-        
-    
+
+    fn name(&self) -> Rc<str> {
+        Rc::clone(&self.name.lexeme)
+    }
+
+    fn as_function(&self) -> Option<&LoxFunction> {
+        Some(self)
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        match &self.body {
+            FunctionBody::Tree { closure, .. } => tracer.environment(closure),
+            FunctionBody::Bytecode { upvalues, this, .. } => {
+                tracer.upvalues(upvalues);
+                if let Some(this) = this {
+                    tracer.object(this);
+                }
+            }
+        }
+    }
 }
 
 impl Display for LoxFunction {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {       
-            write!(f, "<fn {}>", self.name.as_string())       
+            write!(f, "<fn {}>", self.name.lexeme)       
     }
-}
\ No newline at end of file
+}
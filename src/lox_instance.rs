@@ -0,0 +1,86 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{self, Debug, Display};
+use std::rc::Rc;
+
+use crate::callable::*;
+use crate::error::*;
+use crate::gc::*;
+use crate::interpreter::*;
+use crate::lox_class::*;
+use crate::object::*;
+use crate::token::*;
+
+pub struct LoxInstance {
+    klass: Rc<LoxClass>,
+    fields: RefCell<HashMap<Rc<str>, Object>>,
+}
+
+impl LoxInstance {
+    pub fn new(klass: Rc<LoxClass>) -> Self {
+        Self {
+            klass,
+            fields: RefCell::new(HashMap::new()),
+        }
+    }
+
+    pub fn get(instance: &Rc<LoxInstance>, name: &Token, interpreter: &Interpreter) -> Result<Object, LoxResult> {
+        if let Some(value) = instance.fields.borrow().get(&*name.lexeme) {
+            return Ok(value.clone());
+        }
+
+        if let Some(method) = instance.klass.find_method(&name.lexeme) {
+            let bound = method.bind(&Object::Instance(Rc::clone(instance)), interpreter);
+            let arity = bound.arity();
+            return Ok(Object::Func(Callable {
+                func: Rc::new(bound),
+                arity,
+            }));
+        }
+
+        Err(LoxResult::runtime_error(
+            name,
+            &format!("Undefined property '{}'.", name.as_string()),
+        ))
+    }
+
+    pub fn has_field(&self, name: &Token) -> bool {
+        self.fields.borrow().contains_key(&*name.lexeme)
+    }
+
+    pub fn set(&self, name: &Token, value: Object) {
+        self.fields.borrow_mut().insert(Rc::clone(&name.lexeme), value);
+    }
+}
+
+impl Trace for LoxInstance {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.object(&Object::Class(Rc::clone(&self.klass)));
+        match self.fields.try_borrow() {
+            Ok(fields) => {
+                for value in fields.values() {
+                    tracer.object(value);
+                }
+            }
+            Err(_) => tracer.opaque(),
+        }
+    }
+}
+
+impl PartialEq for LoxInstance {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Display for LoxInstance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} instance", self.klass.name())
+    }
+}
+
+impl Debug for LoxInstance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "<{} instance>", self.klass.name())
+    }
+}
@@ -0,0 +1,186 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::callable::*;
+use crate::error::*;
+use crate::gc::*;
+use crate::interpreter::*;
+use crate::object::*;
+use crate::token::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ListMethodKind {
+    Len,
+    Push,
+    Pop,
+    Insert,
+    Remove,
+}
+
+pub struct ListMethod {
+    list: Rc<RefCell<Vec<Object>>>,
+    name: Token,
+    kind: ListMethodKind,
+}
+
+impl ListMethod {
+    pub fn lookup(list: &Rc<RefCell<Vec<Object>>>, name: &Token) -> Result<Object, LoxResult> {
+        let kind = match &*name.lexeme {
+            "len" => ListMethodKind::Len,
+            "push" => ListMethodKind::Push,
+            "pop" => ListMethodKind::Pop,
+            "insert" => ListMethodKind::Insert,
+            "remove" => ListMethodKind::Remove,
+            _ => {
+                return Err(LoxResult::runtime_error(
+                    name,
+                    &format!("Undefined list method '{}'.", name.as_string()),
+                ));
+            }
+        };
+        let method = ListMethod {
+            list: Rc::clone(list),
+            name: name.dup(),
+            kind,
+        };
+        let arity = method.arity();
+        Ok(Object::Func(Callable {
+            func: Rc::new(method),
+            arity,
+        }))
+    }
+}
+
+impl LoxCallable for ListMethod {
+    fn call(&self, interpreter: &Interpreter, arguments: Vec<Object>) -> Result<Object, LoxResult> {
+        if matches!(self.kind, ListMethodKind::Push | ListMethodKind::Insert) {
+            interpreter.allocate(std::mem::size_of::<Object>(), Some(self.name.span))?;
+        }
+        let mut list = self.list.borrow_mut();
+        match self.kind {
+            ListMethodKind::Len => Ok(Object::Num(list.len() as f64)),
+            ListMethodKind::Push => {
+                list.push(arguments[0].clone());
+                Ok(Object::Nil)
+            }
+            ListMethodKind::Pop => list
+                .pop()
+                .ok_or_else(|| LoxResult::runtime_error(&self.name, "Can't pop from an empty list.")),
+            ListMethodKind::Insert => {
+                // Inserting at `len` appends, so the valid range is one wider
+                // than for reads.
+                let index = list_index(&self.name, &arguments[0], list.len() + 1)?;
+                list.insert(index, arguments[1].clone());
+                Ok(Object::Nil)
+            }
+            ListMethodKind::Remove => {
+                let index = list_index(&self.name, &arguments[0], list.len())?;
+                Ok(list.remove(index))
+            }
+        }
+    }
+
+    fn arity(&self) -> usize {
+        match self.kind {
+            ListMethodKind::Len | ListMethodKind::Pop => 0,
+            ListMethodKind::Push | ListMethodKind::Remove => 1,
+            ListMethodKind::Insert => 2,
+        }
+    }
+
+    fn name(&self) -> Rc<str> {
+        Rc::clone(&self.name.lexeme)
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.object(&Object::List(Rc::clone(&self.list)));
+    }
+}
+
+pub fn list_index(token: &Token, index: &Object, len: usize) -> Result<usize, LoxResult> {
+    match index {
+        Object::Num(n) if n.fract() != 0.0 => {
+            Err(LoxResult::runtime_error(token, "List index must be an integer."))
+        }
+        Object::Num(n) if *n < 0.0 => Err(LoxResult::runtime_error(
+            token,
+            &format!("List index {} is negative.", n),
+        )),
+        Object::Num(n) if *n as usize >= len => Err(LoxResult::runtime_error(
+            token,
+            &format!("List index {} out of bounds for length {}.", n, len),
+        )),
+        Object::Num(n) => Ok(*n as usize),
+        _ => Err(LoxResult::runtime_error(token, "List index must be a number.")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token_type::*;
+    use crate::Lux;
+
+
+    #[test]
+    fn can_read_and_write_elements() {
+        let mut lux = Lux::new();
+        lux.run(
+            "var a = [1, 2, 3];
+            a[1] = a[0] + a[2];
+            var result = a[1];",
+        )
+        .unwrap();
+        assert_eq!(lux.global("result"), Object::Num(4.0));
+    }
+
+    #[test]
+    fn lists_are_shared_by_reference() {
+        let mut lux = Lux::new();
+        lux.run(
+            "var a = [];
+            var b = a;
+            b.push(\"x\");
+            b.push(\"y\");
+            var len = a.len();
+            var last = a.pop();
+            a.insert(0, 0);
+            var first = a.remove(0);",
+        )
+        .unwrap();
+        assert_eq!(lux.global("len"), Object::Num(2.0));
+        assert_eq!(lux.global("last"), Object::Str("y".into()));
+        assert_eq!(lux.global("first"), Object::Num(0.0));
+        assert_eq!(format!("{}", lux.global("b")), "[\"x\"]");
+    }
+
+    #[test]
+    fn lists_can_contain_themselves() {
+        let mut lux = Lux::new();
+        lux.run(
+            "var a = [1];
+            a.push(a);
+            var b = [1];
+            b.push(b);
+            var same = a == a;
+            var equal = a == b;
+            var different = a == [1, [2]];
+            var printed = \"${a}\";",
+        )
+        .unwrap();
+        assert_eq!(lux.global("same"), Object::Bool(true));
+        assert_eq!(lux.global("equal"), Object::Bool(true));
+        assert_eq!(lux.global("different"), Object::Bool(false));
+        assert_eq!(lux.global("printed"), Object::Str("[1, [...]]".into()));
+        assert_eq!(lux.global("a").to_string(), "[1, [...]]");
+    }
+
+    #[test]
+    fn out_of_bounds_and_negative_indexes_are_errors() {
+        let token = Token::new(TokenType::LeftBracket, "[".to_string(), None, 0);
+        assert!(list_index(&token, &Object::Num(3.0), 3).is_err());
+        assert!(list_index(&token, &Object::Num(-1.0), 3).is_err());
+        assert!(list_index(&token, &Object::Num(0.5), 3).is_err());
+        assert_eq!(list_index(&token, &Object::Num(2.0), 3).unwrap(), 2);
+    }
+}
@@ -0,0 +1,274 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::callable::*;
+use crate::error::*;
+use crate::gc::*;
+use crate::interpreter::*;
+use crate::object::*;
+use crate::token::*;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum MapKey {
+    Num(u64),
+    Str(Rc<str>),
+    Bool(bool),
+    Nil,
+}
+
+impl MapKey {
+    pub fn from_object(token: &Token, key: &Object) -> Result<MapKey, LoxResult> {
+        match key {
+            // -0 and 0 compare equal as numbers, so they must be the same key.
+            Object::Num(n) if *n == 0.0 => Ok(MapKey::Num(0f64.to_bits())),
+            Object::Num(n) => Ok(MapKey::Num(n.to_bits())),
+            Object::Str(s) => Ok(MapKey::Str(s.clone())),
+            Object::Bool(b) => Ok(MapKey::Bool(*b)),
+            Object::Nil => Ok(MapKey::Nil),
+            _ => Err(LoxResult::runtime_error(
+                token,
+                "Map keys must be numbers, strings, booleans or nil.",
+            )),
+        }
+    }
+
+    pub fn to_object(&self) -> Object {
+        match self {
+            MapKey::Num(bits) => Object::Num(f64::from_bits(*bits)),
+            MapKey::Str(s) => Object::Str(s.clone()),
+            MapKey::Bool(b) => Object::Bool(*b),
+            MapKey::Nil => Object::Nil,
+        }
+    }
+}
+
+// Entries are kept in insertion order so that printing and `keys()` are
+// deterministic; `index` maps each key to its position in `entries`.
+#[derive(Debug, Default)]
+pub struct LoxMap {
+    entries: Vec<(MapKey, Object)>,
+    index: HashMap<MapKey, usize>,
+}
+
+impl LoxMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn contains(&self, key: &MapKey) -> bool {
+        self.index.contains_key(key)
+    }
+
+    pub fn get(&self, key: &MapKey) -> Option<&Object> {
+        self.index.get(key).map(|i| &self.entries[*i].1)
+    }
+
+    pub fn insert(&mut self, key: MapKey, value: Object) {
+        if let Some(i) = self.index.get(&key) {
+            self.entries[*i].1 = value;
+        } else {
+            self.index.insert(key.clone(), self.entries.len());
+            self.entries.push((key, value));
+        }
+    }
+
+    pub fn remove(&mut self, key: &MapKey) -> Option<Object> {
+        let i = self.index.remove(key)?;
+        let (_, value) = self.entries.remove(i);
+        for position in self.index.values_mut() {
+            if *position > i {
+                *position -= 1;
+            }
+        }
+        Some(value)
+    }
+
+    pub fn keys(&self) -> Vec<Object> {
+        self.entries.iter().map(|(key, _)| key.to_object()).collect()
+    }
+
+    pub fn values(&self) -> Vec<Object> {
+        self.entries.iter().map(|(_, value)| value.clone()).collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Object, &Object)> {
+        self.entries.iter().map(|(key, value)| (key.to_object(), value))
+    }
+
+    // Whether both maps have the same keys, in any order, with values that
+    // `equal` considers the same.
+    pub fn same_entries(&self, other: &LoxMap, mut equal: impl FnMut(&Object, &Object) -> bool) -> bool {
+        self.len() == other.len()
+            && self
+                .entries
+                .iter()
+                .all(|(key, value)| other.get(key).is_some_and(|other| equal(value, other)))
+    }
+}
+
+impl fmt::Display for MapKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_object())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MapMethodKind {
+    Len,
+    Has,
+    Keys,
+    Values,
+    Remove,
+}
+
+pub struct MapMethod {
+    map: Rc<RefCell<LoxMap>>,
+    name: Token,
+    kind: MapMethodKind,
+}
+
+impl MapMethod {
+    pub fn lookup(map: &Rc<RefCell<LoxMap>>, name: &Token) -> Result<Object, LoxResult> {
+        let kind = match &*name.lexeme {
+            "len" => MapMethodKind::Len,
+            "has" => MapMethodKind::Has,
+            "keys" => MapMethodKind::Keys,
+            "values" => MapMethodKind::Values,
+            "remove" => MapMethodKind::Remove,
+            _ => {
+                return Err(LoxResult::runtime_error(
+                    name,
+                    &format!("Undefined map method '{}'.", name.as_string()),
+                ));
+            }
+        };
+        let method = MapMethod {
+            map: Rc::clone(map),
+            name: name.dup(),
+            kind,
+        };
+        let arity = method.arity();
+        Ok(Object::Func(Callable {
+            func: Rc::new(method),
+            arity,
+        }))
+    }
+}
+
+impl LoxCallable for MapMethod {
+    fn call(&self, interpreter: &Interpreter, arguments: Vec<Object>) -> Result<Object, LoxResult> {
+        let mut map = self.map.borrow_mut();
+        match self.kind {
+            MapMethodKind::Len => Ok(Object::Num(map.len() as f64)),
+            MapMethodKind::Has => {
+                let key = MapKey::from_object(&self.name, &arguments[0])?;
+                Ok(Object::Bool(map.contains(&key)))
+            }
+            MapMethodKind::Keys => interpreter.new_list(map.keys(), Some(self.name.span)),
+            MapMethodKind::Values => interpreter.new_list(map.values(), Some(self.name.span)),
+            MapMethodKind::Remove => {
+                let key = MapKey::from_object(&self.name, &arguments[0])?;
+                map.remove(&key).ok_or_else(|| {
+                    LoxResult::runtime_error(&self.name, &format!("Undefined key {}.", key))
+                })
+            }
+        }
+    }
+
+    fn arity(&self) -> usize {
+        match self.kind {
+            MapMethodKind::Len | MapMethodKind::Keys | MapMethodKind::Values => 0,
+            MapMethodKind::Has | MapMethodKind::Remove => 1,
+        }
+    }
+
+    fn name(&self) -> Rc<str> {
+        Rc::clone(&self.name.lexeme)
+    }
+
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.object(&Object::Map(Rc::clone(&self.map)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::token_type::*;
+    use crate::Lux;
+
+
+    #[test]
+    fn can_read_write_and_remove_entries() {
+        let mut lux = Lux::new();
+        lux.run(
+            "var m = {\"a\": 1, 2: \"two\"};
+            m[\"b\"] = m[\"a\"] + 1;
+            m[true] = nil;
+            var hasA = m.has(\"a\");
+            var removed = m.remove(\"a\");
+            var gone = !m.has(\"a\");
+            var keys = m.keys();
+            var len = m.len();",
+        )
+        .unwrap();
+        assert_eq!(lux.global("hasA"), Object::Bool(true));
+        assert_eq!(lux.global("removed"), Object::Num(1.0));
+        assert_eq!(lux.global("gone"), Object::Bool(true));
+        assert_eq!(lux.global("len"), Object::Num(3.0));
+        assert_eq!(format!("{}", lux.global("keys")), "[2, \"b\", true]");
+        assert_eq!(format!("{}", lux.global("m")), "{2: \"two\", \"b\": 2, true: nil}");
+    }
+
+    #[test]
+    fn maps_compare_by_contents() {
+        let mut lux = Lux::new();
+        lux.run(
+            "var same = {\"a\": 1, \"b\": [2]} == {\"b\": [2], \"a\": 1};
+            var different = {\"a\": 1} != {\"a\": 2};",
+        )
+        .unwrap();
+        assert_eq!(lux.global("same"), Object::Bool(true));
+        assert_eq!(lux.global("different"), Object::Bool(true));
+    }
+
+    #[test]
+    fn maps_can_contain_themselves() {
+        let mut lux = Lux::new();
+        lux.run(
+            "var m = {};
+            m[\"k\"] = m;
+            var n = {};
+            n[\"k\"] = n;
+            var same = m == m;
+            var equal = m == n;
+            var wrapped = {\"list\": [m]};
+            wrapped[\"list\"].push(wrapped);",
+        )
+        .unwrap();
+        assert_eq!(lux.global("same"), Object::Bool(true));
+        assert_eq!(lux.global("equal"), Object::Bool(true));
+        assert_eq!(lux.global("m").to_string(), "{\"k\": {...}}");
+        assert_eq!(
+            lux.global("wrapped").to_string(),
+            "{\"list\": [{\"k\": {...}}, {...}]}"
+        );
+    }
+
+    #[test]
+    fn only_hashable_values_can_be_keys() {
+        let token = Token::new(TokenType::LeftBracket, "[".to_string(), None, 0);
+        let list = Object::List(Rc::new(RefCell::new(Vec::new())));
+        assert!(MapKey::from_object(&token, &list).is_err());
+        assert_eq!(
+            MapKey::from_object(&token, &Object::Num(-0.0)).unwrap(),
+            MapKey::from_object(&token, &Object::Num(0.0)).unwrap()
+        );
+    }
+}
@@ -1,12 +1,18 @@
 //https://github.com/UncleScientist/lox-ast
 #![allow(dead_code, unused_imports)]
+mod diagnostic;
+use diagnostic::Diagnostics;
 mod error;
 use error::*;
+mod exec_signal;
+mod call_frame;
 mod token_type;
 use token_type::*;
 mod object;
 mod token;
 use token::*;
+mod interner;
+mod span;
 mod scanner;
 use scanner::*;
 mod parser;
@@ -20,8 +26,19 @@ mod environment;
 mod callable;
 mod native_functions;
 mod lox_function;
+mod lox_class;
+mod lox_instance;
+mod lox_list;
+mod lox_map;
 mod resolver;
 use resolver::*;
+mod chunk;
+mod compiler;
+use compiler::*;
+mod vm;
+use vm::*;
+mod gc;
+mod budget;
 //use ast_printer::AstPrinter;
 
 use std::env::args;
@@ -31,13 +48,87 @@ use std::io::{self, BufRead, BufReader, Read, Write, stdout};
 
 
 
+const USAGE: &str = "Usage: lox ast [--error-format=human|json] [--max-call-depth=N] [--max-steps=N] [--time-limit=MS] [--max-memory=BYTES] [--vm] [Script]";
+
+// Scripts run on a thread of their own, with a bigger stack than the main
+// thread is sure to have, so that they can recurse DEFAULT_MAX_CALL_DEPTH
+// calls deep even in debug builds. The stack limit is raised to match,
+// leaving some of the stack spare.
+const INTERPRETER_STACK_SIZE: usize = 32 * 1024 * 1024;
+const INTERPRETER_STACK_LIMIT: usize = 30 * 1024 * 1024;
+
 fn main() {
-    let args: Vec<String> = args().collect();
+    let interpreter = std::thread::Builder::new()
+        .stack_size(INTERPRETER_STACK_SIZE)
+        .spawn(run_main)
+        .expect("failed to start the interpreter thread");
+    if interpreter.join().is_err() {
+        std::process::exit(70);
+    }
+}
+
+// Removes `--name=value` from the arguments and returns the value.
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let prefix = format!("--{}=", name);
+    let i = args.iter().position(|arg| arg.starts_with(&prefix))?;
+    Some(args.remove(i)[prefix.len()..].to_string())
+}
+
+// Removes `--name` from the arguments, returning whether it was there.
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    let flag = format!("--{}", name);
+    match args.iter().position(|arg| *arg == flag) {
+        Some(i) => {
+            args.remove(i);
+            true
+        }
+        None => false,
+    }
+}
+
+fn usage() -> ! {
+    println!("{}", USAGE);
+    std::process::exit(64);
+}
+
+fn run_main() {
+    let mut args: Vec<String> = args().collect();
     let mut lux = Lux::new();
+    lux.interpreter.set_stack_limit(INTERPRETER_STACK_LIMIT);
+    if let Some(format) = take_option(&mut args, "error-format") {
+        match diagnostic::ErrorFormat::parse(&format) {
+            Some(format) => diagnostic::set_error_format(format),
+            None => usage(),
+        }
+    }
+    if let Some(depth) = take_option(&mut args, "max-call-depth") {
+        match depth.parse() {
+            Ok(depth) => lux.interpreter.set_max_call_depth(depth),
+            Err(_) => usage(),
+        }
+    }
+    if let Some(steps) = take_option(&mut args, "max-steps") {
+        match steps.parse() {
+            Ok(steps) => lux.interpreter.set_max_steps(Some(steps)),
+            Err(_) => usage(),
+        }
+    }
+    if let Some(millis) = take_option(&mut args, "time-limit") {
+        match millis.parse() {
+            Ok(millis) => lux.interpreter.set_time_limit(Some(std::time::Duration::from_millis(millis))),
+            Err(_) => usage(),
+        }
+    }
+    if let Some(bytes) = take_option(&mut args, "max-memory") {
+        match bytes.parse() {
+            Ok(bytes) => lux.interpreter.set_max_memory(Some(bytes)),
+            Err(_) => usage(),
+        }
+    }
+    lux.use_vm = take_flag(&mut args, "vm");
     println!("args: {:?}, {}", args, args.len());
     if args.len() > 2 {
-        println!("Usage: lox ast [Script]");
-        std::process::exit(64);
+        usage();
     } else if args.len() == 2 {
         let _ = lux.run_file(&args[1]);
     } else {
@@ -47,18 +138,30 @@ fn main() {
 
 struct Lux {
     interpreter: Interpreter,
+    // Compile scripts to bytecode and run them on the VM instead of walking
+    // the tree.
+    use_vm: bool,
 }
 
 impl Lux {
     pub fn new() -> Self {
         Lux {
             interpreter: Interpreter::new(),
+            use_vm: false,
         }
     }
 
     pub fn run_file(&mut self, path: &String) -> io::Result<()> {
         let buf = std::fs::read_to_string(path)?;
-        if self.run(buf.as_str()).is_err() {
+        diagnostic::set_source(path, &buf);
+        if let Err(errors) = self.run(buf.as_str()) {
+            Lux::report(&errors);
+            if errors
+                .iter()
+                .any(|e| matches!(e, LoxResult::RuntimeError { .. } | LoxResult::ResourceLimit { .. }))
+            {
+                std::process::exit(70);
+            }
             std::process::exit(65);
         }
         // match self.run(buf.as_str()) {
@@ -80,11 +183,9 @@ impl Lux {
                 if line.is_empty() {
                     break;
                 }
-                match self.run(line.as_str()) {
-                    Ok(_) => (),
-                    Err(_) => {
-                        // ingnore: error was already reported
-                    }
+                diagnostic::set_source("<stdin>", &line);
+                if let Err(errors) = self.run(line.as_str()) {
+                    Lux::report(&errors);
                 }
             } else {
                 break;
@@ -94,19 +195,61 @@ impl Lux {
         }
     }
 
-    pub fn run(&mut self, source: &str) -> Result<(), LoxResult> {
-        let mut scanner = Scanner::new(source);
-        let tokens = scanner.scan_tokens()?;
-        let mut parser = Parser::new(tokens);
-        let statements = Rc::new(parser.parse()?);
-        if parser.success() {
-            let resolver = Resolver::new(&self.interpreter);
-            resolver.resolve(&statements)?;
-
-             self.interpreter.interpret(&statements);
-            
-        } 
-        Ok(())
-        
+    // Runs the script unless scanning, parsing or resolving it found any
+    // errors, in which case all of them are returned.
+    pub fn run(&mut self, source: &str) -> Result<(), Vec<LoxResult>> {
+        let diagnostics = Diagnostics::new();
+        let mut scanner = Scanner::new(source, &diagnostics, self.interpreter.symbols());
+        let tokens = scanner.scan_tokens();
+        let mut parser = Parser::new(tokens, &diagnostics);
+        let statements = Rc::new(parser.parse());
+
+        let resolver = Resolver::new(&self.interpreter, &diagnostics);
+        if let Err(e) = resolver.resolve(&statements) {
+            diagnostics.report(e);
+        }
+        for warning in diagnostics.take_warnings() {
+            diagnostic::emit(&warning);
+        }
+        if diagnostics.has_errors() {
+            return Err(diagnostics.into_errors());
+        }
+
+        if self.use_vm {
+            let script = Compiler::new().compile(&statements).map_err(|e| vec![e])?;
+            return Vm::new(&self.interpreter).interpret(script).map_err(|e| vec![e]);
+        }
+        self.interpreter.interpret(&statements).map_err(|e| vec![e])
+    }
+
+    fn report(errors: &[LoxResult]) {
+        for error in errors {
+            error.report();
+        }
+        // The count would not be valid JSON.
+        if diagnostic::error_format() == diagnostic::ErrorFormat::Json {
+            return;
+        }
+        match errors.len() {
+            0 => {}
+            1 => eprintln!("1 error"),
+            n => eprintln!("{} errors", n),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Lux {
+    // Reads a global variable the tests' scripts defined.
+    pub(crate) fn global(&self, name: &str) -> object::Object {
+        let token = Token::new(TokenType::Identifier, name.to_string(), None, 0);
+        self.interpreter.globals.borrow().get(&token).unwrap()
+    }
+
+    // Runs `source` on a fresh interpreter and returns the global `name`.
+    pub(crate) fn evaluate(source: &str, name: &str) -> object::Object {
+        let mut lux = Lux::new();
+        lux.run(source).unwrap();
+        lux.global(name)
     }
 }
@@ -1,3 +1,4 @@
+use std::rc::Rc;
 use std::time::SystemTime;
 
 use crate::callable::*;
@@ -5,7 +6,10 @@ use crate::interpreter::*;
 use crate::object::*;
 use crate::error::*;
 
-pub struct Nativeclock;
+pub struct Nativeclock {
+    pub name: Rc<str>,
+}
+
 impl LoxCallable for Nativeclock {
     fn call(&self, _interpreter: &Interpreter, _arguments: Vec<Object>) -> Result<Object, LoxResult> {
         match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH){
@@ -18,4 +22,8 @@ impl LoxCallable for Nativeclock {
     fn arity(&self) -> usize {
         0
     }
+
+    fn name(&self) -> Rc<str> {
+        Rc::clone(&self.name)
+    }
 }
\ No newline at end of file
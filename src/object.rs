@@ -1,31 +1,140 @@
 use core::fmt;
 use std::backtrace;
 use std::cmp::*;
+use std::cell::RefCell;
+use std::rc::Rc;
 use crate::callable::*;
+use crate::lox_class::*;
+use crate::lox_instance::*;
+use crate::lox_map::*;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone)]
 pub enum Object {
     Num(f64),
-    Str(String),
+    Str(Rc<str>),
     Bool(bool),
     Func(Callable),
+    Class(Rc<LoxClass>),
+    Instance(Rc<LoxInstance>),
+    List(Rc<RefCell<Vec<Object>>>),
+    Map(Rc<RefCell<LoxMap>>),
     Nil,
     ArithmeticError,
 }
 
 impl fmt::Display for Object {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.fmt_guarded(f, &mut Vec::new())
+    }
+}
+
+impl PartialEq for Object {
+    fn eq(&self, other: &Self) -> bool {
+        self.eq_guarded(other, &mut Vec::new())
+    }
+}
+
+impl Object {
+    // `printing` holds the collections whose contents are being printed, so
+    // that one containing itself prints as `[...]` or `{...}` instead of
+    // forever.
+    fn fmt_guarded(&self, f: &mut fmt::Formatter<'_>, printing: &mut Vec<*const ()>) -> fmt::Result {
         match self {
             Object::Num(n) => write!(f, "{}", n),
             Object::Str(s) => write!(f, "{}", s),
             Object::Nil => write!(f, "nil"),
             Object::Bool(b) => write!(f, "{}", b),
             Object::Func(_) => write!(f, "{}", "func"),
+            Object::Class(c) => write!(f, "{}", c),
+            Object::Instance(i) => write!(f, "{}", i),
+            Object::List(list) => {
+                let pointer = Rc::as_ptr(list) as *const ();
+                if printing.contains(&pointer) {
+                    return write!(f, "[...]");
+                }
+                printing.push(pointer);
+                write!(f, "[")?;
+                for (i, element) in list.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    element.fmt_nested(f, printing)?;
+                }
+                printing.pop();
+                write!(f, "]")
+            }
+            Object::Map(map) => {
+                let pointer = Rc::as_ptr(map) as *const ();
+                if printing.contains(&pointer) {
+                    return write!(f, "{{...}}");
+                }
+                printing.push(pointer);
+                write!(f, "{{")?;
+                for (i, (key, value)) in map.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    key.fmt_nested(f, printing)?;
+                    write!(f, ": ")?;
+                    value.fmt_nested(f, printing)?;
+                }
+                printing.pop();
+                write!(f, "}}")
+            }
             Object::ArithmeticError => panic!("Should not print ArithmeticError"),
         }
     }
-}
 
+    // Formats a value that appears inside a collection, quoting strings so
+    // that `["1", 1]` doesn't print as `[1, 1]`.
+    fn fmt_nested(&self, f: &mut fmt::Formatter<'_>, printing: &mut Vec<*const ()>) -> fmt::Result {
+        match self {
+            Object::Str(s) => write!(f, "{:?}", s),
+            _ => self.fmt_guarded(f, printing),
+        }
+    }
+
+    // Collections compare by contents. `comparing` holds the pairs already
+    // being compared: meeting one again means a cycle, and it is equal as
+    // far as the rest of the comparison can tell.
+    fn eq_guarded(&self, other: &Self, comparing: &mut Vec<(*const (), *const ())>) -> bool {
+        match (self, other) {
+            (Object::Num(l), Object::Num(r)) => l == r,
+            (Object::Str(l), Object::Str(r)) => l == r,
+            (Object::Bool(l), Object::Bool(r)) => l == r,
+            (Object::Func(l), Object::Func(r)) => l == r,
+            (Object::Class(l), Object::Class(r)) => l == r,
+            (Object::Instance(l), Object::Instance(r)) => l == r,
+            (Object::List(l), Object::List(r)) => {
+                let pair = (Rc::as_ptr(l) as *const (), Rc::as_ptr(r) as *const ());
+                if Rc::ptr_eq(l, r) || comparing.contains(&pair) {
+                    return true;
+                }
+                let (l, r) = (l.borrow(), r.borrow());
+                if l.len() != r.len() {
+                    return false;
+                }
+                comparing.push(pair);
+                let equal = l.iter().zip(r.iter()).all(|(l, r)| l.eq_guarded(r, comparing));
+                comparing.pop();
+                equal
+            }
+            (Object::Map(l), Object::Map(r)) => {
+                let pair = (Rc::as_ptr(l) as *const (), Rc::as_ptr(r) as *const ());
+                if Rc::ptr_eq(l, r) || comparing.contains(&pair) {
+                    return true;
+                }
+                comparing.push(pair);
+                let equal = l.borrow().same_entries(&r.borrow(), |l, r| l.eq_guarded(r, comparing));
+                comparing.pop();
+                equal
+            }
+            (Object::Nil, Object::Nil) => true,
+            (Object::ArithmeticError, Object::ArithmeticError) => true,
+            _ => false,
+        }
+    }
+}
 
 // impl Object {
 //     pub fn is_truthy(&self) -> bool {
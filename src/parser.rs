@@ -1,3 +1,4 @@
+use crate::diagnostic::Diagnostics;
 use crate::error::*;
 use crate::expr::*;
 use crate::object::*;
@@ -9,26 +10,33 @@ use std::rc::Rc;
 
 pub struct Parser<'a> {
     tokens: &'a [Token],
+    diagnostics: &'a Diagnostics,
     current: usize,
     had_error: bool,
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(tokens: &'a [Token]) -> Self {
+    pub fn new(tokens: &'a [Token], diagnostics: &'a Diagnostics) -> Self {
         Parser {
             tokens,
+            diagnostics,
             current: 0,
             had_error: false,
         }
     }
 
-    pub fn parse(&mut self) -> Result<Vec<Rc<Stmt>>, LoxResult> {
+    // Declarations that fail to parse are reported to the diagnostics and
+    // left out, and parsing resumes at the next statement.
+    pub fn parse(&mut self) -> Vec<Rc<Stmt>> {
         let mut statements = Vec::new();
         while !self.is_at_end() {
-            statements.push(self.declaration()?)
+            match self.declaration() {
+                Ok(statement) => statements.push(statement),
+                Err(e) => self.diagnostics.report(e),
+            }
         }
 
-        Ok(statements)
+        statements
     }
 
     pub fn success(&self) -> bool {
@@ -65,6 +73,12 @@ impl<'a> Parser<'a> {
        
     }
 
+    fn check_next(&self, ttype: &TokenType) -> bool {
+        self.tokens
+            .get(self.current + 1)
+            .is_some_and(|token| token.is(ttype))
+    }
+
     fn is_match(&mut self, types: &[TokenType]) -> bool {
         for t in types {
             if self.check(t) {
@@ -80,8 +94,11 @@ impl<'a> Parser<'a> {
     }
 
     fn declaration(&mut self) -> Result<Rc<Stmt>, LoxResult> {
-        let result = if  self.is_match(&[TokenType::Fun]) {
-            self.function("function")
+        let result = if self.is_match(&[TokenType::Class]) {
+            self.class_declaration()
+        } else if self.check(&TokenType::Fun) && self.check_next(&TokenType::Identifier) {
+            self.advance();
+            Ok(Rc::new(Stmt::Function(self.function("function")?)))
         } else if self.is_match(&[TokenType::Var]) {
             self.var_declaration()
         } else {
@@ -101,6 +118,12 @@ impl<'a> Parser<'a> {
             self.consume(TokenType::SemiColon, "Expect ';' after break")?;
             return Ok(Rc::new(Stmt::Break(Rc::new(BreakStmt { token }))));
         }
+
+        if self.is_match(&[TokenType::Continue]) {
+            let token = self.previous().dup();
+            self.consume(TokenType::SemiColon, "Expect ';' after continue")?;
+            return Ok(Rc::new(Stmt::Continue(Rc::new(ContinueStmt { token }))));
+        }
         
         if self.is_match(&[TokenType::For]) {
             return self.for_statement();
@@ -130,6 +153,7 @@ impl<'a> Parser<'a> {
     }
 
     fn for_statement(&mut self) -> Result<Rc<Stmt>, LoxResult> {
+         let keyword = self.previous().dup();
          self.consume(TokenType::LeftParen, "Expect '(' after for .")?;
          let initiallizer = if self.is_match(&[TokenType::SemiColon]) {
              None
@@ -139,14 +163,14 @@ impl<'a> Parser<'a> {
             Some(self.expression_statement()?)
          };
 
-         let condition = if self.is_match(&[TokenType::SemiColon]) {
+         let condition = if self.check(&TokenType::SemiColon) {
             None
          } else {           
             Some(self.expression()?)
          };
           self.consume(TokenType::SemiColon, "Expect ';' after loop condition. ")?;
           
-         let increment  = if self.is_match(&[TokenType::RightParen])  {
+         let increment  = if self.check(&TokenType::RightParen)  {
             None
          } else {            
             Some(self.expression()?)
@@ -154,28 +178,21 @@ impl<'a> Parser<'a> {
        
           self.consume(TokenType::RightParen, "Expect ')' after for clauses. ")?;
 
-          let mut body = self.statement()?;
+          let body = self.statement()?;
 
-          if let Some(incr) = increment {
-            body = Rc::new(Stmt::Block(Rc::new(BlockStmt {
-                 statements:  Rc::new(vec![
-                    body, 
-                    Rc::new(Stmt::Expression(Rc::new(ExpressionStmt{
-                         expression: Rc::new(incr)
-                        }))),
-                ])
-            })));
-          }
-
-          body = Rc::new(Stmt::While(Rc::new(WhileStmt { 
+          // The increment lives on the while node rather than at the end of
+          // the body so that `continue` doesn't skip it.
+          let mut body = Rc::new(Stmt::While(Rc::new(WhileStmt { 
             condition: if let Some(cond) = condition{
                 Rc::new(cond)
             } else{
                 Rc::new(Expr::Literal(Rc::new(LiteralExpr { 
-                    value: Some(Object::Bool(true))
+                    value: Some(Object::Bool(true)),
+                    token: keyword,
                 })))
             }, 
             body: body,
+            increment: increment.map(Rc::new),
         })));
 
         if let Some(init) = initiallizer {
@@ -226,7 +243,7 @@ impl<'a> Parser<'a> {
         //vprintln!("{:?}", condition);
         //self.consume(TokenType::LeftParen, "Excpect '(' after while ")?;
         let body = self.statement()?;
-        Ok(Stmt::While(Rc::new(WhileStmt { condition, body })))
+        Ok(Stmt::While(Rc::new(WhileStmt { condition, body, increment: None })))
     }
 
     fn var_declaration(&mut self) -> Result<Rc<Stmt>, LoxResult> {
@@ -252,11 +269,45 @@ impl<'a> Parser<'a> {
         Ok(Rc::new(Stmt::Expression(Rc::new(ExpressionStmt { expression: Rc::new(expr) }))))
     }
 
-    fn function(&mut self, kind:&str) -> Result<Rc<Stmt>, LoxResult>{
+    fn class_declaration(&mut self) -> Result<Rc<Stmt>, LoxResult> {
+        let name = self.consume(TokenType::Identifier, "Expect class name")?;
+
+        let superclass = if self.is_match(&[TokenType::Less]) {
+            let name = self.consume(TokenType::Identifier, "Expect superclass name")?;
+            Some(Rc::new(VariableExpr { name }))
+        } else {
+            None
+        };
+
+        self.consume(TokenType::LeftBrace, "Expect '{' before class body")?;
+
+        let mut methods = Vec::new();
+        while !self.check(&TokenType::RightBrace) && !self.is_at_end() {
+            methods.push(self.function("method")?);
+        }
+        self.consume(TokenType::RightBrace, "Expect '}' after class body")?;
+
+        Ok(Rc::new(Stmt::Class(Rc::new(ClassStmt {
+            name,
+            superclass,
+            methods: Rc::new(methods),
+        }))))
+    }
+
+    fn function(&mut self, kind:&str) -> Result<Rc<FunctionStmt>, LoxResult>{
         let name = self.consume(
             TokenType::Identifier, &format!("Expect {kind} name"))?;
         self.consume(TokenType::LeftParen, &format!("Expect '(' after {kind} name"))?;
+        let params = self.parameters()?;
+        self.consume(TokenType::LeftBrace, &format!("Expect '{{' before {kind} body"))?;
+        let body = Rc::new(self.block()?);
 
+        Ok(Rc::new(FunctionStmt { name, params:Rc::new(params), body }))
+
+    }
+
+    // Parses a parameter list up to and including the closing ')'.
+    fn parameters(&mut self) -> Result<Vec<Token>, LoxResult> {
         let mut params = Vec::new();
         if !self.check(&TokenType::RightParen) {
             params.push(self.consume(
@@ -265,7 +316,7 @@ impl<'a> Parser<'a> {
                 if params.len() >= 255 {
                     if !self.had_error {
                         let peek = self.peek().dup();
-                        self.error(
+                        self.report(
                             &peek, "Can't have more than 255 parameters");
                         self.had_error = true;
                     }
@@ -276,12 +327,49 @@ impl<'a> Parser<'a> {
             }
         }
         self.consume(TokenType::RightParen, &format!("Expect ')' parameters"))?;
-        self.consume(TokenType::LeftBrace, &format!("Expect '{{' before {kind} body"))?;
-        let body = Rc::new(self.block()?);
-
-        Ok(Rc::new(Stmt::Function(Rc::new(FunctionStmt
-             { name, params:Rc::new(params), body }))))
+        Ok(params)
+    }
 
+    fn lambda(&mut self) -> Result<Expr, LoxResult> {
+        let keyword = self.previous().dup();
+        self.consume(TokenType::LeftParen, "Expect '(' after 'fun'")?;
+        let params = self.parameters()?;
+        self.consume(TokenType::LeftBrace, "Expect '{' before function body")?;
+        let body = Rc::new(self.block()?);
+        Ok(Expr::Lambda(Rc::new(LambdaExpr { keyword, params: Rc::new(params), body })))
+    }
+
+    // `(a, b) => a + b` is shorthand for `fun (a, b) { return a + b; }`.
+    fn arrow_function(&mut self) -> Result<Expr, LoxResult> {
+        let keyword = self.previous().dup();
+        let params = self.parameters()?;
+        let arrow = self.consume(TokenType::Arrow, "Expect '=>' after parameters")?;
+        let value = self.assignment()?;
+        let body = vec![Rc::new(Stmt::Return(Rc::new(ReturnStmt {
+            keyword: arrow,
+            value: Some(Rc::new(value)),
+        })))];
+        Ok(Expr::Lambda(Rc::new(LambdaExpr { keyword, params: Rc::new(params), body: Rc::new(body) })))
+    }
+
+    // Called just after a '(' to decide whether it opens the parameter list
+    // of an arrow function rather than a grouping.
+    fn is_arrow_function(&self) -> bool {
+        let mut i = self.current;
+        let token = |i: usize| self.tokens.get(i).map(|t| t.token_type());
+        if token(i) != Some(TokenType::RightParen) {
+            loop {
+                if token(i) != Some(TokenType::Identifier) {
+                    return false;
+                }
+                i += 1;
+                if token(i) != Some(TokenType::Comma) {
+                    break;
+                }
+                i += 1;
+            }
+        }
+        token(i) == Some(TokenType::RightParen) && token(i + 1) == Some(TokenType::Arrow)
     }
 
     fn block(&mut self) -> Result<Vec<Rc<Stmt>>, LoxResult> {
@@ -306,8 +394,21 @@ impl<'a> Parser<'a> {
                     name: expr.name.dup(),
                     value: Rc::new(value),
                 })));
+            } else if let Expr::Index(index) = expr {
+                return Ok(Expr::IndexSet(Rc::new(IndexSetExpr {
+                    object: Rc::clone(&index.object),
+                    bracket: index.bracket.dup(),
+                    index: Rc::clone(&index.index),
+                    value: Rc::new(value),
+                })));
+            } else if let Expr::Get(get) = expr {
+                return Ok(Expr::Set(Rc::new(SetExpr {
+                    object: Rc::clone(&get.object),
+                    name: get.name.dup(),
+                    value: Rc::new(value),
+                })));
             }
-            self.error(&equals, "Invalid assignment target ");
+            self.report(&equals, "Invalid assignment target ");
         }
         Ok(expr)
     }
@@ -423,7 +524,7 @@ impl<'a> Parser<'a> {
                 if arguments.len() >= 255{
                     if !self.had_error{
                         let peek = self.peek().dup();
-                        LoxResult::runtime_error(
+                        self.report(
                             &peek, "Can't have more than 255 arguments");
                         self.had_error = true;
                     }
@@ -442,6 +543,18 @@ impl<'a> Parser<'a> {
         loop {
             if self.is_match(&[TokenType::LeftParen]) {
                 expr = self.finish_call(&Rc::new(expr))?;                
+            } else if self.is_match(&[TokenType::Dot]) {
+                let name = self.consume(
+                    TokenType::Identifier, "Expect property name after '.'")?;
+                expr = Expr::Get(Rc::new(GetExpr { object: Rc::new(expr), name }));
+            } else if self.is_match(&[TokenType::LeftBracket]) {
+                let index = self.expression()?;
+                let bracket = self.consume(TokenType::RightBracket, "Expect ']' after index")?;
+                expr = Expr::Index(Rc::new(IndexExpr {
+                    object: Rc::new(expr),
+                    bracket,
+                    index: Rc::new(index),
+                }));
             } else{
                 break;
             }
@@ -457,22 +570,44 @@ impl<'a> Parser<'a> {
         if self.is_match(&[TokenType::False]) {
             return Ok(Expr::Literal(Rc::new(LiteralExpr {
                 value: Some(Object::Bool(false)),
+                token: self.previous().dup(),
             })));
         }
         if self.is_match(&[TokenType::True]) {
             return Ok(Expr::Literal(Rc::new(LiteralExpr {
                 value: Some(Object::Bool(true)),
+                token: self.previous().dup(),
             })));
         }
         if self.is_match(&[TokenType::Nil]) {
             return Ok(Expr::Literal(Rc::new(LiteralExpr {
                 value: Some(Object::Nil),
+                token: self.previous().dup(),
             })));
         }
 
         if self.is_match(&[TokenType::Number, TokenType::String]) {
             return Ok(Expr::Literal(Rc::new(LiteralExpr {
                 value: self.previous().literal.clone(),
+                token: self.previous().dup(),
+            })));
+        }
+
+        if self.is_match(&[TokenType::Super]) {
+            let keyword = self.previous().dup();
+            self.consume(TokenType::Dot, "Expect '.' after 'super'")?;
+            let method = self.consume(
+                TokenType::Identifier, "Expect superclass method name")?;
+            return Ok(Expr::Super(Rc::new(SuperExpr { keyword, method })));
+        }
+
+        if self.is_match(&[TokenType::Interpolation]) {
+            return self.interpolation();
+        }
+
+        if self.is_match(&[TokenType::This]) {
+            return Ok(Expr::This(Rc::new(ThisExpr {
+                keyword: self.previous().dup(),
             })));
         }
 
@@ -482,7 +617,45 @@ impl<'a> Parser<'a> {
             })));
         }
 
+        if self.is_match(&[TokenType::LeftBracket]) {
+            let bracket = self.previous().dup();
+            let mut elements = Vec::new();
+            if !self.check(&TokenType::RightBracket) {
+                elements.push(Rc::new(self.expression()?));
+                while self.is_match(&[TokenType::Comma]) {
+                    elements.push(Rc::new(self.expression()?));
+                }
+            }
+            let close_bracket = self.consume(TokenType::RightBracket, "Expect ']' after list elements")?;
+            return Ok(Expr::List(Rc::new(ListExpr { bracket, elements, close_bracket })));
+        }
+
+        if self.is_match(&[TokenType::LeftBrace]) {
+            let brace = self.previous().dup();
+            let mut keys = Vec::new();
+            let mut values = Vec::new();
+            if !self.check(&TokenType::RightBrace) {
+                loop {
+                    keys.push(Rc::new(self.expression()?));
+                    self.consume(TokenType::Colon, "Expect ':' after map key")?;
+                    values.push(Rc::new(self.expression()?));
+                    if !self.is_match(&[TokenType::Comma]) {
+                        break;
+                    }
+                }
+            }
+            let close_brace = self.consume(TokenType::RightBrace, "Expect '}' after map entries")?;
+            return Ok(Expr::Map(Rc::new(MapExpr { brace, keys, values, close_brace })));
+        }
+
+        if self.is_match(&[TokenType::Fun]) {
+            return self.lambda();
+        }
+
         if self.is_match(&[TokenType::LeftParen]) {
+            if self.is_arrow_function() {
+                return self.arrow_function();
+            }
             let expr = self.expression()?;
             self.consume(TokenType::RightParen, "Expected ')' after expression.")?;
             return Ok(Expr::Grouping(Rc::new(GroupingExpr {
@@ -494,6 +667,34 @@ impl<'a> Parser<'a> {
         //Err(LoxResult::error(self.peek().line, "Expected expression."))
     }
 
+    fn interpolation(&mut self) -> Result<Expr, LoxResult> {
+        let token = self.previous().dup();
+        let mut parts = vec![Rc::new(Expr::Literal(Rc::new(LiteralExpr {
+            value: token.literal.clone(),
+            token: token.dup(),
+        })))];
+        loop {
+            parts.push(Rc::new(self.expression()?));
+            // The scanner hands us the text up to the next `${`, or the rest
+            // of the string once there are no more.
+            if self.is_match(&[TokenType::Interpolation]) {
+                parts.push(Rc::new(Expr::Literal(Rc::new(LiteralExpr {
+                    value: self.previous().literal.clone(),
+                    token: self.previous().dup(),
+                }))));
+            } else {
+                let tail = self.consume(
+                    TokenType::String, "Expect '}' after interpolated expression")?;
+                parts.push(Rc::new(Expr::Literal(Rc::new(LiteralExpr {
+                    value: tail.literal.clone(),
+                    token: tail,
+                }))));
+                break;
+            }
+        }
+        Ok(Expr::Interpolation(Rc::new(InterpolationExpr { token, parts })))
+    }
+
     fn consume(&mut self, ttype: TokenType, message: &str) -> Result<Token, LoxResult> {
         if self.check(&ttype) {
             Ok(self.advance().dup())
@@ -531,4 +732,10 @@ impl<'a> Parser<'a> {
         self.had_error = true;
         LoxResult::parse_error(token, message)
     }
+
+    // For errors that don't need the parser to resynchronize.
+    fn report(&mut self, token: &Token, message: &str) {
+        let error = self.error(token, message);
+        self.diagnostics.report(error);
+    }
 }
@@ -3,24 +3,88 @@ use std::cell::RefCell;
 use std::rc::Rc;
 use std::ops::Deref;
 
+use crate::diagnostic::{Diagnostic, Diagnostics, Note};
+use crate::span::Span;
 use crate::token::*;
 use crate::interpreter::*;
 use crate::error::*;
 use crate::stmt::*;
 use crate::expr::*; 
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FunctionType {
+    None,
+    Function,
+    Method,
+    Initializer,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ClassType {
+    None,
+    Class,
+    Subclass,
+}
+
+// What declared a local, which decides the warnings it can get.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LocalKind {
+    Variable,
+    Parameter,
+    // Functions, classes, `this` and `super`.
+    Other,
+}
+
+// A name declared in a local scope.
+#[derive(Debug, Clone, Copy)]
+struct Local {
+    kind: LocalKind,
+    // Its index in the environment the interpreter creates for the scope.
+    slot: usize,
+    defined: bool,
+    used: bool,
+    declared_at: Span,
+}
+
+impl Local {
+    fn new(kind: LocalKind, slot: usize, declared_at: Span) -> Self {
+        Local { kind, slot, defined: false, used: false, declared_at }
+    }
+
+    // `this` and `super` have no declaration in the source, and are alone
+    // in their scope.
+    fn implicit() -> Self {
+        Local { defined: true, ..Local::new(LocalKind::Other, 0, Span::default()) }
+    }
+}
+
+// Names starting with `_` are deliberately unused or shadowing.
+fn is_silenced(name: &str) -> bool {
+    name.starts_with('_')
+}
+
+type Scope = RefCell<HashMap<Rc<str>, Local>>;
+
 pub struct Resolver<'a> {
     interpreter: &'a Interpreter,
-    scopes: RefCell<Vec<RefCell<HashMap<String, bool>>>>,
-    had_error : RefCell<bool>
+    diagnostics: &'a Diagnostics,
+    scopes: RefCell<Vec<Scope>>,
+    had_error : RefCell<bool>,
+    current_function: RefCell<FunctionType>,
+    current_class: RefCell<ClassType>,
+    loop_depth: RefCell<usize>,
 }
 
 impl<'a> Resolver<'a> {
-    pub fn new(interpreter:&'a Interpreter) -> Self {
+    pub fn new(interpreter:&'a Interpreter, diagnostics: &'a Diagnostics) -> Self {
         Self {
             interpreter,
+            diagnostics,
             scopes: RefCell::new(Vec::new()),
-            had_error: RefCell::new(false)
+            had_error: RefCell::new(false),
+            current_function: RefCell::new(FunctionType::None),
+            current_class: RefCell::new(ClassType::None),
+            loop_depth: RefCell::new(0),
         }
     }
 
@@ -31,6 +95,10 @@ impl<'a> Resolver<'a> {
         Ok(())
     }
 
+    pub fn success(&self) -> bool {
+        !*self.had_error.borrow()
+    }
+
     fn resolve_stmt(&self, stmt: Rc<Stmt>) ->  Result<(), LoxResult>  {
         stmt.accept(stmt.clone(), self)
     }
@@ -44,81 +112,199 @@ impl<'a> Resolver<'a> {
     }
 
     fn end_scope(&self) {
-        self.scopes.borrow_mut().pop();
+        let Some(scope) = self.scopes.borrow_mut().pop() else {
+            return;
+        };
+        let mut unused: Vec<(Rc<str>, Local)> = scope
+            .into_inner()
+            .into_iter()
+            .filter(|(name, local)| !local.used && !is_silenced(name))
+            .collect();
+        unused.sort_by_key(|(_, local)| local.declared_at.offset);
+        for (name, local) in unused {
+            let (kind, message) = match local.kind {
+                LocalKind::Variable => ("unused-variable", format!("Unused local variable '{}'", name)),
+                LocalKind::Parameter => ("unused-parameter", format!("Unused parameter '{}'", name)),
+                LocalKind::Other => continue,
+            };
+            let mut warning = Diagnostic::warning(kind, &message, Some(local.declared_at));
+            warning.help = Some(format!("if this is intentional, name it '_{}'", name));
+            self.diagnostics.warn(warning);
+        }
     }
 
-    fn declare(&self, name:&Token)  {
-        if let Some(scope) = self.scopes.borrow().last() {
-            if scope.borrow().contains_key(&name.as_string()){
-                self.error(name, "Already a varialble with this name in this scope");
+    fn declare(&self, name:&Token, kind: LocalKind)  {
+        let scopes = self.scopes.borrow();
+        if let Some((scope, enclosing)) = scopes.split_last() {
+            let previous = scope.borrow().get(&*name.lexeme).copied();
+            if let Some(previous) = previous {
+                self.error_with_notes(
+                    name,
+                    "Already a varialble with this name in this scope",
+                    vec![Note::new("previously declared here", Some(previous.declared_at))],
+                );
+            } else if kind != LocalKind::Other && !is_silenced(&name.lexeme) {
+                let shadowed = enclosing
+                    .iter()
+                    .rev()
+                    .find_map(|outer| outer.borrow().get(&*name.lexeme).copied());
+                if let Some(shadowed) = shadowed
+                    && shadowed.kind != LocalKind::Other
+                {
+                    let mut warning = Diagnostic::warning(
+                        "shadowed-variable",
+                        &format!("'{}' shadows an outer local variable", name.as_string()),
+                        Some(name.span),
+                    );
+                    warning.notes.push(Note::new("shadowed declaration here", Some(shadowed.declared_at)));
+                    self.diagnostics.warn(warning);
+                }
             }
-            scope.borrow_mut().insert(name.as_string(), false);
+            let slot = scope.borrow().len();
+            scope.borrow_mut().insert(Rc::clone(&name.lexeme), Local::new(kind, slot, name.span));
         }         
            
     }
 
     fn define(&self, name:&Token) {
-        if !self.scopes.borrow().is_empty() {
-            self.scopes
-            .borrow()
-            .last()
-            .unwrap()
-            .borrow_mut()
-            .insert(name.as_string(), true);
+        if let Some(scope) = self.scopes.borrow().last()
+            && let Some(local) = scope.borrow_mut().get_mut(&*name.lexeme)
+        {
+            local.defined = true;
         }
-        
     }
 
-    fn resolve_local(&self, expr:  Rc<Expr>, name: &Token) {
+    // Only reads count as uses: a local that is only ever assigned is still
+    // unused.
+    fn resolve_local(&self, expr:  Rc<Expr>, name: &Token, is_read: bool) {
         for (scope, map) in self.scopes.borrow().iter().rev().enumerate(){
-            if map.borrow().contains_key(&name.as_string()) {
-                self.interpreter.resolve(expr, scope);
+            if let Some(local) = map.borrow_mut().get_mut(&*name.lexeme) {
+                local.used |= is_read;
+                self.interpreter.resolve(expr, scope, local.slot);
                 return;
             }
         }
     }
 
-    fn resolve_function(&self,  function:&FunctionStmt) -> Result<(), LoxResult>{
+    fn resolve_function(&self, params: &[Token], body: &Rc<Vec<Rc<Stmt>>>, ftype: FunctionType) -> Result<(), LoxResult>{
+        let enclosing_function = self.current_function.replace(ftype);
+        // A function body starts outside of any loop, even when the function
+        // is declared inside one.
+        let enclosing_loop_depth = self.loop_depth.replace(0);
         self.begin_scope();
-        for param in function.params.iter(){
-            self.declare(param);
+        for param in params.iter(){
+            self.declare(param, LocalKind::Parameter);
             self.define(param);
         }
-        self.resolve(&function.body)?;
+        self.resolve(body)?;
         self.end_scope();
+        self.loop_depth.replace(enclosing_loop_depth);
+        self.current_function.replace(enclosing_function);
         Ok(())
 
     }
 
     fn error(&self, token:&Token, message:&str) {
+        self.error_with_notes(token, message, Vec::new());
+    }
+
+    fn error_with_notes(&self, token:&Token, message:&str, notes: Vec<Note>) {
         self.had_error.replace(true);
-        LoxResult::parse_error(token, message);
+        self.diagnostics.report(LoxResult::resolve_error(token, message, notes));
     }
 
 } 
 
 impl<'a> StmtVisitor<()> for Resolver<'a>{
     fn visit_return_stmt(&self, _:Rc<Stmt>, stmt: &ReturnStmt) -> Result<(), LoxResult> {
+        if *self.current_function.borrow() == FunctionType::None {
+            self.error(&stmt.keyword, "Can't return from top-level code");
+        }
         if let Some(value) = &stmt.value{
+            if *self.current_function.borrow() == FunctionType::Initializer {
+                self.error(&stmt.keyword, "Can't return a value from an initializer");
+            }
             self.resolve_expr(value.clone())?;
         }
         Ok(())
     }
 
     fn visit_function_stmt(&self, _:Rc<Stmt>, stmt: &FunctionStmt) -> Result<(), LoxResult> {
-        self.declare(&stmt.name);
+        self.declare(&stmt.name, LocalKind::Other);
         self.define(&stmt.name);
-        self.resolve_function(stmt)?;
+        self.resolve_function(&stmt.params, &stmt.body, FunctionType::Function)?;
+        Ok(())
+    }
+
+    fn visit_class_stmt(&self, _:Rc<Stmt>, stmt: &ClassStmt) -> Result<(), LoxResult> {
+        let enclosing_class = self.current_class.replace(ClassType::Class);
+        self.declare(&stmt.name, LocalKind::Other);
+        self.define(&stmt.name);
+
+        if let Some(superclass) = &stmt.superclass {
+            if superclass.name.lexeme == stmt.name.lexeme {
+                self.error(&superclass.name, "A class can't inherit from itself");
+            }
+            self.current_class.replace(ClassType::Subclass);
+            self.resolve_expr(Rc::new(Expr::Variable(Rc::clone(superclass))))?;
+
+            self.begin_scope();
+            self.scopes
+                .borrow()
+                .last()
+                .unwrap()
+                .borrow_mut()
+                .insert("super".into(), Local::implicit());
+        }
+
+        self.begin_scope();
+        self.scopes
+            .borrow()
+            .last()
+            .unwrap()
+            .borrow_mut()
+            .insert("this".into(), Local::implicit());
+
+        for method in stmt.methods.iter() {
+            let declaration = if &*method.name.lexeme == "init" {
+                FunctionType::Initializer
+            } else {
+                FunctionType::Method
+            };
+            self.resolve_function(&method.params, &method.body, declaration)?;
+        }
+
+        self.end_scope();
+        if stmt.superclass.is_some() {
+            self.end_scope();
+        }
+        self.current_class.replace(enclosing_class);
+        Ok(())
+    }
+
+    fn visit_break_stmt(&self, _:Rc<Stmt>, stmt: &BreakStmt) -> Result<(), LoxResult> {
+        if *self.loop_depth.borrow() == 0 {
+            self.error(&stmt.token, "Can't use 'break' outside of a loop");
+        }
         Ok(())
     }
 
-    fn visit_break_stmt(&self, _:Rc<Stmt>, _stmt: &BreakStmt) -> Result<(), LoxResult> {
+    fn visit_continue_stmt(&self, _:Rc<Stmt>, stmt: &ContinueStmt) -> Result<(), LoxResult> {
+        if *self.loop_depth.borrow() == 0 {
+            self.error(&stmt.token, "Can't use 'continue' outside of a loop");
+        }
         Ok(())
     }
 
     fn visit_while_stmt(&self, _:Rc<Stmt>, stmt:&WhileStmt) -> Result<(), LoxResult> {
         self.resolve_expr(stmt.condition.clone())?;
-        self.resolve_stmt(stmt.body.clone())?;
+        *self.loop_depth.borrow_mut() += 1;
+        let result = self.resolve_stmt(stmt.body.clone());
+        *self.loop_depth.borrow_mut() -= 1;
+        result?;
+        if let Some(increment) = &stmt.increment {
+            self.resolve_expr(increment.clone())?;
+        }
         Ok(())
     }
     
@@ -150,7 +336,7 @@ impl<'a> StmtVisitor<()> for Resolver<'a>{
     }
 
     fn visit_var_stmt(&self, _:Rc<Stmt>, stmt: &VarStmt) -> Result<(), LoxResult> {
-        self.declare(&stmt.name);
+        self.declare(&stmt.name, LocalKind::Variable);
         if let Some(init) = &stmt.initializer {
             self.resolve_expr(init.clone())?;
         }
@@ -170,23 +356,95 @@ impl<'a> ExprVisitor<()> for Resolver<'a>{
          Ok(())
      }
 
-     fn visit_logical_expr(&self, _:Rc<Expr>, _expr: &LogicalExpr) -> Result<(), LoxResult> {
-          Ok(())
+     fn visit_get_expr(&self, _:Rc<Expr>, expr: &GetExpr) -> Result<(), LoxResult> {
+         self.resolve_expr(expr.object.clone())
+     }
+
+     fn visit_index_expr(&self, _:Rc<Expr>, expr: &IndexExpr) -> Result<(), LoxResult> {
+         self.resolve_expr(expr.object.clone())?;
+         self.resolve_expr(expr.index.clone())
+     }
+
+     fn visit_indexset_expr(&self, _:Rc<Expr>, expr: &IndexSetExpr) -> Result<(), LoxResult> {
+         self.resolve_expr(expr.value.clone())?;
+         self.resolve_expr(expr.object.clone())?;
+         self.resolve_expr(expr.index.clone())
+     }
+
+     fn visit_interpolation_expr(&self, _:Rc<Expr>, expr: &InterpolationExpr) -> Result<(), LoxResult> {
+         for part in &expr.parts {
+             self.resolve_expr(part.clone())?;
+         }
+         Ok(())
+     }
+
+     fn visit_lambda_expr(&self, _:Rc<Expr>, expr: &LambdaExpr) -> Result<(), LoxResult> {
+         self.resolve_function(&expr.params, &expr.body, FunctionType::Function)
+     }
+
+     fn visit_list_expr(&self, _:Rc<Expr>, expr: &ListExpr) -> Result<(), LoxResult> {
+         for element in &expr.elements {
+             self.resolve_expr(element.clone())?;
+         }
+         Ok(())
+     }
+
+     fn visit_map_expr(&self, _:Rc<Expr>, expr: &MapExpr) -> Result<(), LoxResult> {
+         for (key, value) in expr.keys.iter().zip(expr.values.iter()) {
+             self.resolve_expr(key.clone())?;
+             self.resolve_expr(value.clone())?;
+         }
+         Ok(())
+     }
+
+     fn visit_set_expr(&self, _:Rc<Expr>, expr: &SetExpr) -> Result<(), LoxResult> {
+         self.resolve_expr(expr.value.clone())?;
+         self.resolve_expr(expr.object.clone())
+     }
+
+     fn visit_super_expr(&self, wrapper:Rc<Expr>, expr: &SuperExpr) -> Result<(), LoxResult> {
+         match *self.current_class.borrow() {
+             ClassType::None => {
+                 self.error(&expr.keyword, "Can't use 'super' outside of a class");
+                 return Ok(());
+             }
+             ClassType::Class => {
+                 self.error(&expr.keyword, "Can't use 'super' in a class with no superclass");
+                 return Ok(());
+             }
+             ClassType::Subclass => {}
+         }
+         self.resolve_local(wrapper, &expr.keyword, true);
+         Ok(())
+     }
+
+     fn visit_this_expr(&self, wrapper:Rc<Expr>, expr: &ThisExpr) -> Result<(), LoxResult> {
+         if *self.current_class.borrow() == ClassType::None {
+             self.error(&expr.keyword, "Can't use 'this' outside of a class");
+             return Ok(());
+         }
+         self.resolve_local(wrapper, &expr.keyword, true);
+         Ok(())
+     }
+
+     fn visit_logical_expr(&self, _:Rc<Expr>, expr: &LogicalExpr) -> Result<(), LoxResult> {
+         self.resolve_expr(expr.left.clone())?;
+         self.resolve_expr(expr.right.clone())
      }
 
      fn visit_assign_expr(&self, wrapper:Rc<Expr>, expr: &AssignExpr) -> Result<(), LoxResult> {
          self.resolve_expr(expr.value.clone())?;
-         self.resolve_local(wrapper, &expr.name);
+         self.resolve_local(wrapper, &expr.name, false);
          Ok(())
      }
 
      fn visit_variable_expr(&self, wrapper:Rc<Expr>, expr: &VariableExpr) -> Result<(), LoxResult> {
         if !self.scopes.borrow().is_empty() &&
-            self.scopes.borrow().last().unwrap().borrow().get(&expr.name.as_string()) == Some(&false) {
+            self.scopes.borrow().last().unwrap().borrow().get(&*expr.name.lexeme).is_some_and(|local| !local.defined) {
                 self.error(
                     &expr.name, "Can't load local variable in its own initizlier");
             } else {
-                self.resolve_local(wrapper, &expr.name);
+                self.resolve_local(wrapper, &expr.name, true);
                
             }
            Ok(())
@@ -213,4 +471,162 @@ impl<'a> ExprVisitor<()> for Resolver<'a>{
     }
 
     
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::*;
+    use crate::scanner::*;
+
+    fn resolves(source: &str) -> bool {
+        let interpreter = Interpreter::new();
+        let diagnostics = Diagnostics::new();
+        let mut scanner = Scanner::new(source, &diagnostics, interpreter.symbols());
+        let tokens = scanner.scan_tokens();
+        let mut parser = Parser::new(tokens, &diagnostics);
+        let statements = Rc::new(parser.parse());
+        assert!(diagnostics.is_empty());
+        let resolver = Resolver::new(&interpreter, &diagnostics);
+        resolver.resolve(&statements).unwrap();
+        resolver.success()
+    }
+
+    #[test]
+    fn break_and_continue_need_a_loop() {
+        assert!(!resolves("break;"));
+        assert!(!resolves("if (true) { continue; }"));
+        assert!(!resolves("while (true) { fun f() { break; } }"));
+        assert!(resolves("while (true) { if (true) break; else continue; }"));
+        assert!(resolves("for (;;) { { continue; } }"));
+    }
+
+    #[test]
+    fn return_needs_a_function() {
+        assert!(!resolves("return;"));
+        assert!(!resolves("{ while (true) { return 1; } }"));
+        assert!(!resolves("class A { init() { return 1; } }"));
+        assert!(resolves("fun f() { while (true) { return 1; } }"));
+        assert!(resolves("class A { init() { return; } m() { return 1; } }"));
+    }
+
+    #[test]
+    fn bad_inheritance_is_rejected() {
+        let messages = |source: &str| -> Vec<String> {
+            let mut lux = crate::Lux::new();
+            let errors = lux.run(source).unwrap_err();
+            errors.iter().map(|e| e.diagnostic().message).collect()
+        };
+        assert_eq!(messages("class A < A {}"), vec!["A class can't inherit from itself (at 'A')"]);
+        assert_eq!(
+            messages("class A { m() { return super.m(); } }"),
+            vec!["Can't use 'super' in a class with no superclass (at 'super')"]
+        );
+        assert_eq!(
+            messages("fun f() { super.m(); }"),
+            vec!["Can't use 'super' outside of a class (at 'super')"]
+        );
+        assert!(resolves("class A { m() {} } class B < A { m() { super.m(); } }"));
+    }
+
+    #[test]
+    fn rejected_programs_are_not_executed() {
+        let mut lux = crate::Lux::new();
+        assert!(lux.run("var ran = false; ran = true; return;").is_err());
+        let token = Token::new(crate::token_type::TokenType::Identifier, "ran".to_string(), None, 0);
+        assert!(lux.interpreter.globals.borrow().get(&token).is_err());
+    }
+
+    #[test]
+    fn errors_from_every_phase_are_collected() {
+        let mut lux = crate::Lux::new();
+        let errors = lux.run("var a = ;\nvar b = 1 ~ 2;\nbreak;\nvar c = 3;").unwrap_err();
+        let found: Vec<(&str, usize)> = errors
+            .iter()
+            .map(|e| e.diagnostic())
+            .map(|d| (d.kind, d.span.unwrap().line))
+            .collect();
+        assert_eq!(found, vec![("syntax", 1), ("syntax", 2), ("syntax", 2), ("resolve", 3)]);
+    }
+
+    fn warnings(source: &str) -> Vec<String> {
+        let interpreter = Interpreter::new();
+        let diagnostics = Diagnostics::new();
+        let mut scanner = Scanner::new(source, &diagnostics, interpreter.symbols());
+        let tokens = scanner.scan_tokens();
+        let statements = Rc::new(Parser::new(tokens, &diagnostics).parse());
+        Resolver::new(&interpreter, &diagnostics).resolve(&statements).unwrap();
+        assert!(diagnostics.is_empty());
+        diagnostics
+            .take_warnings()
+            .iter()
+            .map(|w| format!("{} {}:{}", w.kind, w.span.unwrap().line, w.span.unwrap().column))
+            .collect()
+    }
+
+    #[test]
+    fn unused_locals_and_parameters_are_warned_about() {
+        assert_eq!(
+            warnings("fun f(a, b) {\n  var c = a;\n  var d = 1;\n  return c;\n}"),
+            vec!["unused-parameter 1:10", "unused-variable 3:7"]
+        );
+        assert_eq!(warnings("var global = 1; fun g(_x) { var _unused_too = 2; }"), Vec::<String>::new());
+        assert_eq!(warnings("{ var counter = 0; fun inc() { counter = counter + 1; } inc(); }"), Vec::<String>::new());
+        assert_eq!(warnings("var l = (x) => 1;"), vec!["unused-parameter 1:10"]);
+        assert_eq!(warnings("fun f() { var x = 1; x = 2; }"), vec!["unused-variable 1:15"]);
+    }
+
+    #[test]
+    fn shadowing_an_outer_local_is_warned_about() {
+        assert_eq!(
+            warnings("{ var a = 1; { var a = 2; print a; } print a; }"),
+            vec!["shadowed-variable 1:20"]
+        );
+        assert_eq!(
+            warnings("fun f(n) { return (n) => n; }"),
+            vec!["unused-parameter 1:7", "shadowed-variable 1:20"]
+        );
+        // Globals aren't locals, and `_` silences the warning.
+        assert_eq!(warnings("var a = 1; { var a = 2; print a; }"), Vec::<String>::new());
+        assert_eq!(warnings("{ var _a = 1; { var _a = 2; } }"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn logical_operands_see_locals() {
+        use crate::object::Object;
+        let shadowed = "var a = \"global\";
+            fun f() { var a = \"local\"; return a or \"fallback\"; }
+            var result = f();";
+        assert_eq!(crate::Lux::evaluate(shadowed, "result"), Object::Str("local".into()));
+
+        let parameters = "fun both(x, y) { return x and y; }
+            fun either(x, y) { return x or y; }
+            var result = \"${both(1, 2)} ${either(nil, 3)}\";";
+        assert_eq!(crate::Lux::evaluate(parameters, "result"), Object::Str("2 3".into()));
+    }
+
+    #[test]
+    fn closures_inside_expressions_capture_locals() {
+        use crate::object::Object;
+        let logical = "fun make(n) { return nil or (() => n); }
+            var result = make(5)();";
+        assert_eq!(crate::Lux::evaluate(logical, "result"), Object::Num(5.0));
+
+        let call = "fun apply(g, v) { return g(v); }
+            fun add(n) { return apply((x) => x + n, 1); }
+            var result = add(41);";
+        assert_eq!(crate::Lux::evaluate(call, "result"), Object::Num(42.0));
+
+        let grouping = "fun wrap(n) { var m = n * 2; return ((() => m + n))(); }
+            var result = wrap(2);";
+        assert_eq!(crate::Lux::evaluate(grouping, "result"), Object::Num(6.0));
+
+        let nested = "fun counter() {
+                var count = 0;
+                return () => (count = count + 1) > 2 and count < 10 or false;
+            }
+            var c = counter();
+            c(); c();
+            var result = c();";
+        assert_eq!(crate::Lux::evaluate(nested, "result"), Object::Bool(true));
+    }
+}
@@ -1,96 +1,208 @@
+use crate::diagnostic::Diagnostics;
+use crate::interner::Interner;
 use crate::object::Object;
+use crate::span::Span;
 use crate::{error::LoxResult, token::*, token_type::TokenType};
 use std::f32::consts;
 
-pub struct Scanner {
+pub struct Scanner<'a> {
     source: String,
+    diagnostics: &'a Diagnostics,
     tokens: Vec<Token>,
+    // Byte offsets of the token being scanned and of the next character.
     start: usize,
     current: usize,
+    // Where `current` is, and where `start` was.
     line: usize,
+    column: usize,
+    start_line: usize,
+    start_column: usize,
+    // One entry per `${` we are inside of, counting the `{` opened since
+    // then so that the matching `}` resumes the enclosing string.
+    interpolations: Vec<usize>,
+    symbols: &'a Interner,
+    // How many times a column was worked out by rescanning its line.
+    #[cfg(test)]
+    column_lookups: std::cell::Cell<usize>,
 }
 
-impl Scanner {
-    pub fn new(source: &str) -> Self {
+impl<'a> Scanner<'a> {
+    pub fn new(source: &str, diagnostics: &'a Diagnostics, symbols: &'a Interner) -> Self {
         Self {
             source: source.to_string(),
+            diagnostics,
             tokens: Vec::new(),
             start: 0,
             current: 0,
             line: 1,
+            column: 1,
+            start_line: 1,
+            start_column: 1,
+            interpolations: Vec::new(),
+            symbols,
+            #[cfg(test)]
+            column_lookups: std::cell::Cell::new(0),
         }
     }
 
-    pub fn scan_tokens(&mut self) -> Result<&Vec<Token>, LoxResult> {
-        let mut has_error: Option<LoxResult> = None;
-
+    // Errors are reported to the diagnostics and scanning carries on, so the
+    // token list is always complete up to the Eof.
+    pub fn scan_tokens(&mut self) -> &Vec<Token> {
         while !self.is_at_end() {
             self.start = self.current;
+            self.start_line = self.line;
+            self.start_column = self.column;
             // Here would be the logic to scan a single token
-            match self.scan_token() {
-                Ok(_) => {}
-                Err(e) => {
-                    e.report("");
-                    has_error = Some(e);
-                }
+            if let Err(e) = self.scan_token() {
+                self.diagnostics.report(e);
             }
         }
-        self.tokens.push(Token::eof(self.line));
-        if let Some(e) = has_error {
-            return Err(e);
+        if !self.interpolations.is_empty() {
+            self.diagnostics.report(
+                self.error_from(self.current, self.line, "Unterminated string interpolation."),
+            );
         }
-        Ok(&self.tokens)
+        let eof = Span::new(self.current, 0, self.line, self.column);
+        self.tokens.push(Token::with_span(TokenType::Eof, "EOF".into(), None, eof));
+        &self.tokens
     }
 
     fn is_at_end(&self) -> bool {
         self.current >= self.source.len()
     }
 
+    // The only place the position moves, so that it keeps the line and
+    // column in step.
     fn advance(&mut self) -> char {
-        let c = self.source.chars().nth(self.current).unwrap();
-        self.current += 1;
+        let c = self.peek();
+        self.current += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
         c
     }
 
     fn is_match(&mut self, expected: char) -> bool {
-        if let Some(c) = self.source.chars().nth(self.current) {
-            if c != expected {
-                return false;
-            }
-            self.current += 1;
-            return true;
+        if self.is_at_end() || self.peek() != expected {
+            return false;
         }
-        false
+        self.advance();
+        true
     }
 
     fn peek(&self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
-        self.source.chars().nth(self.current).unwrap()
+        self.source[self.current..].chars().next().unwrap_or('\0')
     }
 
     fn scan_string(&mut self) -> Result<(), LoxResult> {
+        let mut value = String::new();
         while self.peek() != '"' && !self.is_at_end() {
-            if self.peek() == '\n' {
-                self.line += 1;
+            match self.advance() {
+                '\\' => match self.scan_escape() {
+                    Ok(c) => value.push(c),
+                    // Keep the string so the parser doesn't trip over it too.
+                    Err(e) => self.diagnostics.report(e),
+                },
+                '$' if self.peek() == '{' => {
+                    self.advance(); // consume '{'
+                    self.interpolations.push(0);
+                    let value = self.symbols.intern(&value);
+                    self.add_token(TokenType::Interpolation, Some(Object::Str(value)));
+                    return Ok(());
+                }
+                c => value.push(c),
             }
-            self.advance();
         }
 
         if self.is_at_end() {
             // Handle unterminated string error
-            return Err(LoxResult::error(self.line, "Unterminated string."));
+            return Err(self.error_from(self.start, self.start_line, "Unterminated string."));
         }
 
         // The closing ".
         self.advance();
 
-        // TODO: Handle escape sequences here
+        let value = self.symbols.intern(&value);
+        self.add_token(TokenType::String, Some(Object::Str(value)));
+        Ok(())
+    }
 
-        // Trim the surrounding quotes.
-        let value = &self.source[self.start + 1..self.current - 1];
-        self.add_token(TokenType::String, Some(Object::Str(value.to_string())));
+    fn scan_escape(&mut self) -> Result<char, LoxResult> {
+        // Errors underline the whole escape, starting at its backslash.
+        let (from, line) = (self.current - 1, self.line);
+        if self.is_at_end() {
+            return Err(self.error_from(self.start, self.start_line, "Unterminated string."));
+        }
+        match self.advance() {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            '0' => Ok('\0'),
+            '\\' => Ok('\\'),
+            '"' => Ok('"'),
+            '\'' => Ok('\''),
+            '$' => Ok('$'),
+            'u' => self.scan_unicode_escape(from),
+            '\n' => {
+                Err(LoxResult::error_at(
+                    Span::new(from, 1, line, self.column_at(from)),
+                    "Invalid escape sequence '\\' at end of line.",
+                ))
+            }
+            c => Err(self.error_from(from, line, &format!("Invalid escape sequence '\\{}'.", c))),
+        }
+    }
+
+    // Parses the `{1F600}` part of a `\u{1F600}` escape.
+    fn scan_unicode_escape(&mut self, from: usize) -> Result<char, LoxResult> {
+        if !self.is_match('{') {
+            return Err(self.error_from(from, self.line, "Expect '{' after '\\u'."));
+        }
+        let mut digits = String::new();
+        while self.peek().is_ascii_hexdigit() {
+            digits.push(self.advance());
+        }
+        if !self.is_match('}') {
+            return Err(self.error_from(from, self.line, "Expect '}' after unicode escape digits."));
+        }
+        if digits.is_empty() || digits.len() > 6 {
+            return Err(self.error_from(
+                from,
+                self.line,
+                "Unicode escape must have between 1 and 6 hex digits.",
+            ));
+        }
+        u32::from_str_radix(&digits, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or_else(|| {
+                self.error_from(
+                    from,
+                    self.line,
+                    &format!("Invalid unicode escape '\\u{{{}}}'.", digits),
+                )
+            })
+    }
+
+    // A raw string, `r"..."`, keeps its contents verbatim: backslashes are not
+    // escapes, which is handy for regexes and Windows paths.
+    fn scan_raw_string(&mut self) -> Result<(), LoxResult> {
+        let mut value = String::new();
+        while self.peek() != '"' && !self.is_at_end() {
+            value.push(self.advance());
+        }
+
+        if self.is_at_end() {
+            return Err(self.error_from(self.start, self.start_line, "Unterminated string."));
+        }
+
+        // The closing ".
+        self.advance();
+        let value = self.symbols.intern(&value);
+        self.add_token(TokenType::String, Some(Object::Str(value)));
         Ok(())
     }
 
@@ -100,10 +212,7 @@ impl Scanner {
     }
 
     fn peek_next(&self) -> char {
-        if self.current + 1 >= self.source.len() {
-            return '\0';
-        }
-        self.source.chars().nth(self.current + 1).unwrap()
+        self.source[self.current..].chars().nth(1).unwrap_or('\0')
     }
 
     fn number(&mut self) {
@@ -127,7 +236,7 @@ impl Scanner {
     }
 
     fn is_alpha(&self, c: char) -> bool {       
-        c.is_ascii_uppercase() || c.is_ascii_lowercase() 
+        c.is_ascii_uppercase() || c.is_ascii_lowercase() || c == '_'
     }
 
     fn is_alpha_numeric(&self, c: char) -> bool {
@@ -138,6 +247,7 @@ impl Scanner {
         match check {
             "and" => Some(TokenType::And),
             "class" => Some(TokenType::Class),
+            "continue" => Some(TokenType::Continue),
             "else" => Some(TokenType::Else),
             "false" => Some(TokenType::False),
             "for" => Some(TokenType::For),
@@ -189,13 +299,9 @@ impl Scanner {
                         self.advance();
                     }
                 }
-                '\n' => {
-                    self.line += 1;
-                    self.advance();
+                _ if self.is_at_end() => {
+                    return Err(self.error_from(self.start, self.start_line, "Unterminated comment"));
                 }
-                '\0' => {
-                    return Err(LoxResult::error(self.line, "Unterminated comment"));
-                } // End of source
                 _ => {
                     self.advance();
                 }
@@ -209,9 +315,28 @@ impl Scanner {
         match c {
             '(' => self.add_token(TokenType::LeftParen, None),
             ')' => self.add_token(TokenType::RightParen, None),
-            '{' => self.add_token(TokenType::LeftBrace, None),
-            '}' => self.add_token(TokenType::RightBrace, None),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.add_token(TokenType::LeftBrace, None);
+            }
+            '}' => match self.interpolations.last_mut() {
+                Some(0) => {
+                    // This closes a `${`, so carry on with the rest of the string.
+                    self.interpolations.pop();
+                    self.scan_string()?;
+                }
+                Some(depth) => {
+                    *depth -= 1;
+                    self.add_token(TokenType::RightBrace, None);
+                }
+                None => self.add_token(TokenType::RightBrace, None),
+            },
+            '[' => self.add_token(TokenType::LeftBracket, None),
+            ']' => self.add_token(TokenType::RightBracket, None),
             ',' => self.add_token(TokenType::Comma, None),
+            ':' => self.add_token(TokenType::Colon, None),
             '.' => self.add_token(TokenType::Dot, None),
             '-' => self.add_token(TokenType::Minus, None),
             '+' => self.add_token(TokenType::Plus, None),
@@ -221,10 +346,15 @@ impl Scanner {
                 true => self.add_token(TokenType::BangEqual, None),              
                 false => self.add_token(TokenType::Bang, None),               
             },
-            '=' => match self.is_match('=') {
-                true => self.add_token(TokenType::Equal, None),               
-                false =>  self.add_token(TokenType::Assign, None),                  
-            },
+            '=' => {
+                if self.is_match('=') {
+                    self.add_token(TokenType::Equal, None);
+                } else if self.is_match('>') {
+                    self.add_token(TokenType::Arrow, None);
+                } else {
+                    self.add_token(TokenType::Assign, None);
+                }
+            }
             '>' => match self.is_match('=') {
                 true => self.add_token(TokenType::GreaterEqual, None),                
                 false => self.add_token(TokenType::Greater, None),  
@@ -248,10 +378,7 @@ impl Scanner {
                 
             }
 
-            ' ' | '\r' | '\t' => {}
-            '\n' => {
-                self.line += 1;                
-            }
+            ' ' | '\r' | '\t' | '\n' => {}
             '"' => {
                 // String literal scanning would go here
                 self.scan_string()?;                 
@@ -259,19 +386,230 @@ impl Scanner {
             '0'..='9' => {             
                 self.number();
             }
-            _ if self.is_alpha(c) || c == '_' => {
+            'r' if self.peek() == '"' => {
+                self.advance(); // consume the opening "
+                self.scan_raw_string()?;
+            }
+            _ if self.is_alpha(c) => {
                 self.identifier();
             }
             _ => {
-                LoxResult::error(self.line, "Unexpected character.");
+                return Err(self.error_from(self.start, self.start_line, "Unexpected character."));
             }
         };
         Ok(())
     }
 
     fn add_token(&mut self, ttype: TokenType, literal: Option<Object>) {
-        let text = &self.source[self.start..self.current];
-        self.tokens
-            .push(Token::new(ttype, text.to_string(), literal, self.line));
+        let lexeme = self.symbols.intern(&self.source[self.start..self.current]);
+        let span = Span::new(
+            self.start,
+            self.current - self.start,
+            self.start_line,
+            self.start_column,
+        );
+        self.tokens.push(Token::with_span(ttype, lexeme, literal, span));
+    }
+
+    // An error spanning from `offset` on `line` up to the current position.
+    fn error_from(&self, offset: usize, line: usize, message: &str) -> LoxResult {
+        LoxResult::error_at(
+            Span::new(offset, self.current - offset, line, self.column_at(offset)),
+            message,
+        )
+    }
+
+    // The 1-based column, in characters, of the byte at `offset`. Only used
+    // for errors, so that scanning itself stays linear.
+    fn column_at(&self, offset: usize) -> usize {
+        #[cfg(test)]
+        self.column_lookups.set(self.column_lookups.get() + 1);
+        let line_start = self.source[..offset].rfind('\n').map_or(0, |i| i + 1);
+        self.source[line_start..offset].chars().count() + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scan_string_literal(source: &str) -> Result<Object, LoxResult> {
+        let diagnostics = Diagnostics::new();
+        let symbols = Interner::new();
+        let mut scanner = Scanner::new(source, &diagnostics, &symbols);
+        let literal = scanner.scan_tokens()[0].literal.clone();
+        match diagnostics.into_errors().into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(literal.unwrap()),
+        }
+    }
+
+    fn scan(source: &str) -> (Vec<Token>, Vec<LoxResult>) {
+        let diagnostics = Diagnostics::new();
+        let symbols = Interner::new();
+        let tokens = Scanner::new(source, &diagnostics, &symbols).scan_tokens().clone();
+        (tokens, diagnostics.into_errors())
+    }
+
+    #[test]
+    fn handles_escape_sequences() {
+        assert_eq!(
+            scan_string_literal(r#""a\tb\nc \"q\" \\ \u{41}""#).unwrap(),
+            Object::Str("a\tb\nc \"q\" \\ A".into())
+        );
+    }
+
+    #[test]
+    fn invalid_escapes_are_errors() {
+        assert!(scan_string_literal(r#""\q""#).is_err());
+        assert!(scan_string_literal(r#""\u{110000}""#).is_err());
+        assert!(scan_string_literal(r#""\u{}""#).is_err());
+        assert!(scan_string_literal(r#""\u41""#).is_err());
+    }
+
+    #[test]
+    fn invalid_escape_reports_its_line() {
+        match scan_string_literal("\n\n\"ok\\x\"") {
+            Err(LoxResult::Error { span, .. }) => assert_eq!(span.line, 3),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn interpolation_is_split_into_parts() {
+        let (tokens, errors) = scan(r#""a ${x + {"k": 1}["k"]} b ${y} c""#);
+        assert!(errors.is_empty());
+        let types: Vec<TokenType> = tokens
+            .iter()
+            .map(|t| t.token_type())
+            .collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Interpolation,
+                TokenType::Identifier,
+                TokenType::Plus,
+                TokenType::LeftBrace,
+                TokenType::String,
+                TokenType::Colon,
+                TokenType::Number,
+                TokenType::RightBrace,
+                TokenType::LeftBracket,
+                TokenType::String,
+                TokenType::RightBracket,
+                TokenType::Interpolation,
+                TokenType::Identifier,
+                TokenType::String,
+                TokenType::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn unterminated_interpolation_is_an_error() {
+        assert_eq!(scan(r#""a ${x"#).1.len(), 1);
+        assert_eq!(
+            scan_string_literal(r#""cost: \${x}""#).unwrap(),
+            Object::Str("cost: ${x}".into())
+        );
+    }
+
+    #[test]
+    fn raw_strings_keep_backslashes() {
+        assert_eq!(
+            scan_string_literal(r#"r"C:\temp\new\d+""#).unwrap(),
+            Object::Str(r"C:\temp\new\d+".into())
+        );
+    }
+
+    #[test]
+    fn tokens_carry_their_span() {
+        let (tokens, _) = scan("var x =\n  \"a\nb\" + yy;");
+        assert_eq!(tokens[0].span, Span::new(0, 3, 1, 1));
+        assert_eq!(tokens[1].span, Span::new(4, 1, 1, 5));
+        assert_eq!(tokens[3].span, Span::new(10, 5, 2, 3));
+        assert_eq!(tokens[5].span, Span::new(18, 2, 3, 6));
+        assert_eq!(tokens[7].span, Span::new(21, 0, 3, 9));
+    }
+
+    #[test]
+    fn r_is_still_an_identifier() {
+        let (tokens, _) = scan("r rx");
+        assert!(tokens[0].is(&TokenType::Identifier));
+        assert!(tokens[1].is(&TokenType::Identifier));
+    }
+
+    #[test]
+    fn scanning_continues_after_an_error() {
+        let (tokens, errors) = scan("var a = 1 ~ 2;\nvar b = \"\\q\" # 3;");
+        assert_eq!(errors.len(), 3);
+        assert!(matches!(&errors[2], LoxResult::Error { span, .. } if span.line == 2 && span.column == 14));
+        assert_eq!(tokens.iter().filter(|t| t.is(&TokenType::Var)).count(), 2);
+    }
+
+    #[test]
+    fn multi_byte_text_in_strings_and_comments() {
+        let (tokens, errors) = scan("// café ☕\nvar s = \"héllo 👋🏽 wörld\"; /* ñ 🎉\n */ print s;");
+        assert!(errors.is_empty());
+        assert_eq!(tokens[3].literal, Some(Object::Str("héllo 👋🏽 wörld".into())));
+        // Spans are in bytes, columns in characters.
+        assert_eq!(tokens[3].span, Span::new(21, 24, 2, 9));
+        assert_eq!(tokens[5].span, Span::new(62, 5, 3, 5));
+        assert_eq!(tokens[6].span.column, 11);
+    }
+
+    #[test]
+    fn non_ascii_outside_strings_is_an_error() {
+        let (tokens, errors) = scan("var é = 1;\nprint \"ü\" + 🎉;");
+        let spans: Vec<Span> = errors
+            .iter()
+            .map(|e| e.diagnostic().span.unwrap())
+            .collect();
+        assert_eq!(spans, vec![Span::new(4, 2, 1, 5), Span::new(25, 4, 2, 13)]);
+        assert_eq!(tokens.last().unwrap().span, Span::new(30, 0, 2, 15));
+    }
+
+    #[test]
+    fn interpolation_and_escapes_next_to_multi_byte_text() {
+        assert_eq!(
+            scan_string_literal("\"ßtraße\\u{1F600}ü\\n\"").unwrap(),
+            Object::Str("ßtraße😀ü\n".into())
+        );
+        let (tokens, errors) = scan("\"👍 ${name}ñ\"");
+        assert!(errors.is_empty());
+        assert_eq!(tokens[0].literal, Some(Object::Str("👍 ".into())));
+        assert_eq!(tokens[2].literal, Some(Object::Str("ñ".into())));
+    }
+
+    #[test]
+    fn scanning_is_linear() {
+        // Columns are kept up to date as the scanner advances, so only
+        // errors go back over the line to find one.
+        let source = format!("var s = \"{}\"; var t = 1;", "é".repeat(10_000));
+        let diagnostics = Diagnostics::new();
+        let symbols = Interner::new();
+        let mut scanner = Scanner::new(&source, &diagnostics, &symbols);
+        assert_eq!(scanner.scan_tokens().len(), 11);
+        assert!(diagnostics.is_empty());
+        assert_eq!(scanner.column_lookups.get(), 0);
+
+        let source = format!("var s = \"{}\\q\"; @", "é".repeat(10_000));
+        let mut scanner = Scanner::new(&source, &diagnostics, &symbols);
+        scanner.scan_tokens();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(scanner.column_lookups.get(), 2);
+    }
+
+    // Quadratic scanning takes minutes on a single line this long. Run with
+    // `cargo test --release -- --ignored --nocapture scanning_benchmark`.
+    #[test]
+    #[ignore]
+    fn scanning_benchmark() {
+        let source = format!("var s = \"{}\";", "é".repeat(200_000));
+        let start = std::time::Instant::now();
+        let (tokens, errors) = scan(&source);
+        println!("scanning took {:?}", start.elapsed());
+        assert!(errors.is_empty());
+        assert_eq!(tokens.len(), 6);
     }
 }
@@ -0,0 +1,179 @@
+use std::ops::Deref;
+use std::rc::Rc;
+
+use crate::expr::*;
+use crate::stmt::*;
+
+// A range of source text. `offset` and `len` are in bytes; `line` and
+// `column` (both starting at 1) describe where the range begins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub offset: usize,
+    pub len: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn new(offset: usize, len: usize, line: usize, column: usize) -> Self {
+        Self {
+            offset,
+            len,
+            line,
+            column,
+        }
+    }
+
+    // For tokens made up outside the scanner, where only the line is known.
+    pub fn at_line(line: usize) -> Self {
+        Self {
+            offset: 0,
+            len: 0,
+            line,
+            column: 0,
+        }
+    }
+
+    pub fn end(&self) -> usize {
+        self.offset + self.len
+    }
+
+    // The smallest span covering both `self` and `other`.
+    pub fn to(&self, other: &Span) -> Span {
+        let (first, _) = if self.offset <= other.offset {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        Span {
+            offset: first.offset,
+            len: self.end().max(other.end()) - first.offset,
+            line: first.line,
+            column: first.column,
+        }
+    }
+}
+
+impl Expr {
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Assign(e) => e.name.span.to(&e.value.span()),
+            Expr::Binary(e) => e.left.span().to(&e.right.span()),
+            Expr::Call(e) => e.callee.span().to(&e.paren.span),
+            Expr::Get(e) => e.object.span().to(&e.name.span),
+            Expr::Grouping(e) => e.expression.span(),
+            Expr::Index(e) => e.object.span().to(&e.bracket.span),
+            Expr::IndexSet(e) => e.object.span().to(&e.value.span()),
+            Expr::Interpolation(e) => match e.parts.last() {
+                Some(part) => e.token.span.to(&part.span()),
+                None => e.token.span,
+            },
+            Expr::Lambda(e) => match e.body.last().and_then(|stmt| stmt.span()) {
+                Some(body) => e.keyword.span.to(&body),
+                None => e.keyword.span,
+            },
+            Expr::List(e) => e.bracket.span.to(&e.close_bracket.span),
+            Expr::Literal(e) => e.token.span,
+            Expr::Logical(e) => e.left.span().to(&e.right.span()),
+            Expr::Map(e) => e.brace.span.to(&e.close_brace.span),
+            Expr::Set(e) => e.object.span().to(&e.value.span()),
+            Expr::Super(e) => e.keyword.span.to(&e.method.span),
+            Expr::This(e) => e.keyword.span,
+            Expr::Unary(e) => e.operator.span.to(&e.right.span()),
+            Expr::Variable(e) => e.name.span,
+        }
+    }
+}
+
+impl Stmt {
+    // Statements built by the parser always have a span, except for an empty
+    // block, which holds no tokens at all.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            Stmt::Block(s) => span_of(&s.statements),
+            Stmt::Break(s) => Some(s.token.span),
+            Stmt::Class(s) => Some(s.name.span),
+            Stmt::Continue(s) => Some(s.token.span),
+            Stmt::Expression(s) => Some(s.expression.span()),
+            Stmt::Function(s) => Some(match span_of(&s.body) {
+                Some(body) => s.name.span.to(&body),
+                None => s.name.span,
+            }),
+            Stmt::If(s) => {
+                let branch = match &s.else_branch {
+                    Some(else_branch) => else_branch.span(),
+                    None => s.then_branch.span(),
+                };
+                let condition = s.condition.span();
+                Some(branch.map_or(condition, |branch| condition.to(&branch)))
+            }
+            Stmt::Print(s) => Some(s.expression.span()),
+            Stmt::Return(s) => Some(match &s.value {
+                Some(value) => s.keyword.span.to(&value.span()),
+                None => s.keyword.span,
+            }),
+            Stmt::Var(s) => Some(match &s.initializer {
+                Some(initializer) => s.name.span.to(&initializer.span()),
+                None => s.name.span,
+            }),
+            Stmt::While(s) => {
+                let condition = s.condition.span();
+                Some(s.body.span().map_or(condition, |body| condition.to(&body)))
+            }
+        }
+    }
+}
+
+fn span_of(statements: &[Rc<Stmt>]) -> Option<Span> {
+    let spans: Vec<Span> = statements.iter().filter_map(|s| s.span()).collect();
+    match (spans.first(), spans.last()) {
+        (Some(first), Some(last)) => Some(first.to(last)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::*;
+    use crate::scanner::*;
+
+    fn first_statement(source: &str) -> Rc<Stmt> {
+        let diagnostics = crate::diagnostic::Diagnostics::new();
+        let symbols = crate::interner::Interner::new();
+        let mut scanner = Scanner::new(source, &diagnostics, &symbols);
+        let tokens = scanner.scan_tokens();
+        let mut parser = Parser::new(tokens, &diagnostics);
+        parser.parse().remove(0)
+    }
+
+    #[test]
+    fn expressions_cover_their_operands() {
+        let stmt = first_statement("print  foo.bar(1) + -[2, 3][0];");
+        let Stmt::Print(print) = stmt.deref() else {
+            panic!("expected a print statement");
+        };
+        assert_eq!(print.expression.span(), Span::new(7, 23, 1, 8));
+    }
+
+    #[test]
+    fn collection_literals_cover_their_closing_token() {
+        let printed = |source: &str| {
+            let stmt = first_statement(source);
+            let Stmt::Print(print) = stmt.deref() else {
+                panic!("expected a print statement");
+            };
+            print.expression.span()
+        };
+        assert_eq!(printed("print [1, 2 ];"), Span::new(6, 7, 1, 7));
+        assert_eq!(printed("print [];"), Span::new(6, 2, 1, 7));
+        assert_eq!(printed("print {\"a\": 1\n};"), Span::new(6, 9, 1, 7));
+    }
+
+    #[test]
+    fn statements_cover_their_body() {
+        let stmt = first_statement("fun f(a) {\n  return a;\n}");
+        assert_eq!(stmt.span(), Some(Span::new(4, 17, 1, 5)));
+        assert_eq!(first_statement("{}").span(), None);
+    }
+}
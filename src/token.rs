@@ -1,23 +1,34 @@
 use core::fmt;
+use std::rc::Rc;
 
 use crate::object::Object;
+use crate::span::Span;
 use crate::token_type::TokenType;
 
 #[derive(Debug, Clone)]
 pub struct Token {
     pub ttype: TokenType,
-    pub lexeme: String,
+    pub lexeme: Rc<str>,
     pub literal: Option<Object>,
-    pub line: usize,
+    pub span: Span,
 }
 
 impl Token {
-    pub fn new(ttype: TokenType, lexeme: String, literal: Option<Object>, line: usize) -> Self {
+    pub fn new(ttype: TokenType, lexeme: impl Into<Rc<str>>, literal: Option<Object>, line: usize) -> Self {
+        Self {
+            ttype,
+            lexeme: lexeme.into(),
+            literal,
+            span: Span::at_line(line),
+        }
+    }
+
+    pub fn with_span(ttype: TokenType, lexeme: Rc<str>, literal: Option<Object>, span: Span) -> Self {
         Self {
             ttype,
             lexeme,
             literal,
-            line,
+            span,
         }
     }
 
@@ -30,7 +41,7 @@ impl Token {
     }
 
     pub fn as_string(&self) -> String {
-        self.lexeme.clone()
+        self.lexeme.to_string()
     }
 
     pub fn dup(&self) -> Self {
@@ -38,16 +49,16 @@ impl Token {
             ttype: self.ttype.clone(),
             lexeme: self.lexeme.clone(),
             literal: self.literal.clone(),
-            line: self.line,
+            span: self.span,
         }
     }
 
     pub fn eof(line: usize) -> Self {
         Self {
             ttype: TokenType::Eof,
-            lexeme: "EOF".to_string(),
+            lexeme: "EOF".into(),
             literal: None,
-            line,
+            span: Span::at_line(line),
         }
     }
 }
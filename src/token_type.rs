@@ -8,7 +8,10 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
+    Colon,
     Dot,
     Minus,
     Plus,
@@ -20,6 +23,7 @@ pub enum TokenType {
     BangEqual,
     Assign, //('=' )
     Equal,  // ('==' )
+    Arrow,  // ('=>' )
     Greater,
     GreaterEqual,
     Less,
@@ -27,12 +31,14 @@ pub enum TokenType {
     // Literals.
     Identifier,
     String,
+    Interpolation,
     Number,
 
     // Keywords.
     Break,
     And,
     Class,
+    Continue,
     Else,
     False,
     Fun,
@@ -58,7 +64,10 @@ impl fmt::Display for TokenType {
             TokenType::RightParen => write!(f, ")"),
             TokenType::LeftBrace => write!(f, "{{"),
             TokenType::RightBrace => write!(f, "}}"),
+            TokenType::LeftBracket => write!(f, "["),
+            TokenType::RightBracket => write!(f, "]"),
             TokenType::Comma => write!(f, ","),
+            TokenType::Colon => write!(f, ":"),
             TokenType::Dot => write!(f, "."),
             TokenType::Minus => write!(f, "-"),
             TokenType::Plus => write!(f, "+"),
@@ -71,6 +80,7 @@ impl fmt::Display for TokenType {
             TokenType::BangEqual => write!(f, "!="),
             TokenType::Assign => write!(f, "="),
             TokenType::Equal => write!(f, "=="),
+            TokenType::Arrow => write!(f, "=>"),
             TokenType::Greater => write!(f, ">"),
             TokenType::GreaterEqual => write!(f, ">="),
             TokenType::Less => write!(f, "<"),
@@ -79,11 +89,13 @@ impl fmt::Display for TokenType {
             // Literals.
             TokenType::Identifier => write!(f, "identifier"),
             TokenType::String => write!(f, "string"),
+            TokenType::Interpolation => write!(f, "interpolation"),
             TokenType::Number => write!(f, "number"),
 
             // Keywords.
             TokenType::And => write!(f, "and"),
             TokenType::Class => write!(f, "class"),
+            TokenType::Continue => write!(f, "continue"),
             TokenType::Else => write!(f, "else"),
             TokenType::False => write!(f, "false"),
             TokenType::Fun => write!(f, "fun"),
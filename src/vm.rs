@@ -0,0 +1,535 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::callable::*;
+use crate::chunk::*;
+use crate::error::*;
+use crate::interpreter::*;
+use crate::lox_class::*;
+use crate::lox_function::*;
+use crate::lox_instance::*;
+use crate::lox_map::*;
+use crate::object::*;
+use crate::token::*;
+
+// A variable a closure captured. It stays in its slot on the stack while
+// that is in use, and moves into the upvalue once the slot goes away.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Object),
+}
+
+pub type Upvalues = Rc<[Rc<RefCell<Upvalue>>]>;
+
+// A call in progress: the code it runs and the variables it captured, the
+// next instruction, and where its slots start on the stack.
+struct Frame {
+    chunk: Rc<Chunk>,
+    upvalues: Upvalues,
+    ip: usize,
+    base: usize,
+}
+
+// Runs compiled code on its own value and call stacks, so that Lox calls
+// don't recurse on the native stack. Operations on values go through the
+// interpreter, so both backends share their globals, limits and error
+// messages, and calls are recorded on its call stack for backtraces.
+pub struct Vm<'a> {
+    interpreter: &'a Interpreter,
+    stack: Vec<Object>,
+    frames: Vec<Frame>,
+    // The upvalues still pointing into `stack`, ordered by slot.
+    open_upvalues: Vec<(usize, Rc<RefCell<Upvalue>>)>,
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(interpreter: &'a Interpreter) -> Self {
+        Self {
+            interpreter,
+            stack: Vec::new(),
+            frames: Vec::new(),
+            open_upvalues: Vec::new(),
+        }
+    }
+
+    // Runs a compiled script.
+    pub fn interpret(&mut self, script: FunctionProto) -> Result<(), LoxResult> {
+        self.interpreter.start_run();
+        self.stack.push(Object::Nil);
+        self.frames.push(Frame {
+            chunk: script.chunk,
+            upvalues: Rc::new([]),
+            ip: 0,
+            base: 0,
+        });
+        let result = self.run();
+        self.interpreter.collect_garbage();
+        result.map(|_| ())
+    }
+
+    // Calls a compiled function from outside the VM, which has already put a
+    // frame for it on the interpreter's call stack.
+    pub fn call(&mut self, chunk: &Rc<Chunk>, upvalues: &Upvalues, this: Object, arguments: Vec<Object>) -> Result<Object, LoxResult> {
+        self.stack.push(this);
+        self.stack.extend(arguments);
+        self.frames.push(Frame {
+            chunk: Rc::clone(chunk),
+            upvalues: Rc::clone(upvalues),
+            ip: 0,
+            base: 0,
+        });
+        self.run()
+    }
+
+    // Runs until the first frame returns. An error unwinds every frame,
+    // closing the variables captured from them.
+    fn run(&mut self) -> Result<Object, LoxResult> {
+        let result = self.execute().map_err(|error| self.interpreter.with_backtrace(error));
+        if result.is_err() {
+            // The first frame's call was recorded by whoever started it.
+            for _ in 1..self.frames.len() {
+                self.interpreter.pop_frame();
+            }
+            self.frames.clear();
+            self.close_upvalues(0);
+            self.stack.clear();
+        }
+        result
+    }
+
+    fn pop(&mut self) -> Result<Object, LoxResult> {
+        self.stack
+            .pop()
+            .ok_or_else(|| LoxResult::system_error("VM stack underflow."))
+    }
+
+    fn peek(&self) -> Result<&Object, LoxResult> {
+        self.stack
+            .last()
+            .ok_or_else(|| LoxResult::system_error("VM stack underflow."))
+    }
+
+    // Removes the top `n` values, in the order they were pushed.
+    fn pop_n(&mut self, n: u32) -> Result<Vec<Object>, LoxResult> {
+        let Some(start) = self.stack.len().checked_sub(n as usize) else {
+            return Err(LoxResult::system_error("VM stack underflow."));
+        };
+        Ok(self.stack.split_off(start))
+    }
+
+    fn current(&self) -> (Rc<Chunk>, Upvalues, usize, usize) {
+        let frame = self.frames.last().expect("the VM is running a frame");
+        (Rc::clone(&frame.chunk), Rc::clone(&frame.upvalues), frame.ip, frame.base)
+    }
+
+    // The upvalue for `slot`, shared with any closure that already captured
+    // it.
+    fn capture_upvalue(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let mut index = self.open_upvalues.len();
+        while index > 0 {
+            let (open, upvalue) = &self.open_upvalues[index - 1];
+            if *open == slot {
+                return Rc::clone(upvalue);
+            }
+            if *open < slot {
+                break;
+            }
+            index -= 1;
+        }
+        let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+        self.interpreter.track_upvalue(&upvalue);
+        self.open_upvalues.insert(index, (slot, Rc::clone(&upvalue)));
+        upvalue
+    }
+
+    // Moves the values of the slots from `from` up into the upvalues that
+    // captured them.
+    fn close_upvalues(&mut self, from: usize) {
+        while let Some(&(slot, _)) = self.open_upvalues.last() {
+            if slot < from {
+                break;
+            }
+            let (_, upvalue) = self.open_upvalues.pop().unwrap();
+            *upvalue.borrow_mut() = Upvalue::Closed(self.stack[slot].clone());
+        }
+    }
+
+    // Makes a function capturing what `proto` asks for from the frame with
+    // slots from `base` and `upvalues`.
+    fn closure(&mut self, proto: &Rc<FunctionProto>, upvalues: &Upvalues, base: usize, is_initializer: bool) -> LoxFunction {
+        let captured: Upvalues = proto
+            .upvalues
+            .iter()
+            .map(|source| {
+                if source.is_local {
+                    self.capture_upvalue(base + source.index as usize)
+                } else {
+                    Rc::clone(&upvalues[source.index as usize])
+                }
+            })
+            .collect();
+        LoxFunction::compiled(proto, captured, is_initializer)
+    }
+
+    // Calls the value below the `argc` arguments on top of the stack.
+    // Compiled functions and initializers get a frame, and true is returned;
+    // anything else is called through the interpreter, leaving its result.
+    fn call_value(&mut self, argc: usize, paren: &Token) -> Result<bool, LoxResult> {
+        let interpreter = self.interpreter;
+        let slot = self.stack.len() - argc - 1;
+        let callee = self.stack[slot].clone();
+        match &callee {
+            Object::Func(callable) => {
+                if let Some((chunk, upvalues, this)) = callable.func.as_function().and_then(LoxFunction::bytecode) {
+                    interpreter.check_arity(callable.func.arity(), argc, paren)?;
+                    interpreter.push_frame(callable.func.name(), paren)?;
+                    self.stack[slot] = this.cloned().unwrap_or(Object::Nil);
+                    self.frames.push(Frame {
+                        chunk: Rc::clone(chunk),
+                        upvalues: Rc::clone(upvalues),
+                        ip: 0,
+                        base: slot,
+                    });
+                    return Ok(true);
+                }
+            }
+            Object::Class(klass) => {
+                if let Some(initializer) = klass.find_method("init")
+                    && let Some((chunk, upvalues, _)) = initializer.bytecode()
+                {
+                    interpreter.check_arity(initializer.arity(), argc, paren)?;
+                    interpreter.allocate(std::mem::size_of::<LoxInstance>(), Some(paren.span))?;
+                    interpreter.push_frame(klass.name(), paren)?;
+                    self.stack[slot] = Object::Instance(Rc::new(LoxInstance::new(Rc::clone(klass))));
+                    self.frames.push(Frame {
+                        chunk: Rc::clone(chunk),
+                        upvalues: Rc::clone(upvalues),
+                        ip: 0,
+                        base: slot,
+                    });
+                    return Ok(true);
+                }
+            }
+            _ => {}
+        }
+        let arguments = self.stack.split_off(slot + 1);
+        self.stack.pop();
+        let value = interpreter.call_value(callee, arguments, paren)?;
+        self.stack.push(value);
+        Ok(false)
+    }
+
+    fn execute(&mut self) -> Result<Object, LoxResult> {
+        let interpreter = self.interpreter;
+        let (mut chunk, mut upvalues, mut ip, mut base) = self.current();
+        loop {
+            let op = chunk.code[ip];
+            ip += 1;
+            interpreter.step(|| Some(chunk.spans[ip - 1]))?;
+            match op {
+                OpCode::Constant(constant) => self.stack.push(chunk.constants[constant as usize].clone()),
+                OpCode::Nil => self.stack.push(Object::Nil),
+                OpCode::Pop => {
+                    self.pop()?;
+                }
+                OpCode::GetGlobal(name) => {
+                    let value = interpreter.globals.borrow().get(&chunk.tokens[name as usize])?;
+                    self.stack.push(value);
+                }
+                OpCode::SetGlobal(name) => {
+                    let value = self.peek()?.clone();
+                    interpreter.globals.borrow_mut().assign(&chunk.tokens[name as usize], value)?;
+                }
+                OpCode::DefineGlobal(name) => {
+                    let value = self.pop()?;
+                    interpreter.define_global(&chunk.tokens[name as usize], value)?;
+                }
+                OpCode::GetLocal(slot) => {
+                    let value = self.stack[base + slot as usize].clone();
+                    self.stack.push(value);
+                }
+                OpCode::SetLocal(slot) => {
+                    let value = self.peek()?.clone();
+                    self.stack[base + slot as usize] = value;
+                }
+                OpCode::GetUpvalue(index) => {
+                    let value = match &*upvalues[index as usize].borrow() {
+                        Upvalue::Open(slot) => self.stack[*slot].clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetUpvalue(index) => {
+                    let value = self.peek()?.clone();
+                    match &mut *upvalues[index as usize].borrow_mut() {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.pop()?;
+                }
+                OpCode::Jump(to) => {
+                    // Loops jump back here, so a long one can't outrun the
+                    // collector.
+                    interpreter.collect_garbage();
+                    ip = to as usize;
+                }
+                OpCode::JumpIfFalse(to) => {
+                    if !interpreter.is_truthy(self.peek()?) {
+                        ip = to as usize;
+                    }
+                }
+                OpCode::JumpIfTrue(to) => {
+                    if interpreter.is_truthy(self.peek()?) {
+                        ip = to as usize;
+                    }
+                }
+                OpCode::Unary(operator) => {
+                    let right = self.pop()?;
+                    let value = interpreter.unary(&chunk.tokens[operator as usize], right)?;
+                    self.stack.push(value);
+                }
+                OpCode::Binary(operator) => {
+                    let right = self.pop()?;
+                    let left = self.pop()?;
+                    let value = interpreter.binary(&chunk.tokens[operator as usize], left, right)?;
+                    self.stack.push(value);
+                }
+                OpCode::Print => {
+                    let value = self.pop()?;
+                    println!("{value}");
+                }
+                OpCode::Call { paren, argc } => {
+                    interpreter.collect_garbage();
+                    self.frames.last_mut().expect("the VM is running a frame").ip = ip;
+                    if self.call_value(argc as usize, &chunk.tokens[paren as usize])? {
+                        (chunk, upvalues, ip, base) = self.current();
+                    }
+                }
+                OpCode::Closure(function) => {
+                    let proto = &chunk.functions[function as usize];
+                    let function = self.closure(proto, &upvalues, base, false);
+                    self.stack.push(Object::Func(Callable {
+                        func: Rc::new(function),
+                        arity: proto.params.len(),
+                    }));
+                }
+                OpCode::Class(class) => {
+                    let class = &chunk.classes[class as usize];
+                    let superclass = match &class.superclass {
+                        Some(token) => Some(interpreter.superclass(self.peek()?.clone(), token)?),
+                        None => None,
+                    };
+                    let methods = class
+                        .methods
+                        .iter()
+                        .map(|method| {
+                            let name = Rc::clone(&method.name.lexeme);
+                            let function = self.closure(method, &upvalues, base, &*name == "init");
+                            (name, Rc::new(function))
+                        })
+                        .collect();
+                    let klass = LoxClass::new(Rc::clone(&class.name.lexeme), superclass, methods);
+                    self.stack.push(Object::Class(Rc::new(klass)));
+                }
+                OpCode::GetProperty(name) => {
+                    let object = self.pop()?;
+                    let value = interpreter.get_property(object, &chunk.tokens[name as usize])?;
+                    self.stack.push(value);
+                }
+                OpCode::CheckInstance(name) => {
+                    if !matches!(self.peek()?, Object::Instance(_)) {
+                        return Err(LoxResult::runtime_error(
+                            &chunk.tokens[name as usize],
+                            "Only instances have fields.",
+                        ));
+                    }
+                }
+                OpCode::SetProperty(name) => {
+                    let value = self.pop()?;
+                    let object = self.pop()?;
+                    let value = interpreter.set_property(object, &chunk.tokens[name as usize], value)?;
+                    self.stack.push(value);
+                }
+                OpCode::GetSuper(method) => {
+                    let superclass = self.pop()?;
+                    let this = self.pop()?;
+                    let value = interpreter.super_method(superclass, this, &chunk.tokens[method as usize])?;
+                    self.stack.push(value);
+                }
+                OpCode::GetIndex(bracket) => {
+                    let index = self.pop()?;
+                    let object = self.pop()?;
+                    let value = interpreter.get_index(object, index, &chunk.tokens[bracket as usize])?;
+                    self.stack.push(value);
+                }
+                OpCode::SetIndex(bracket) => {
+                    let value = self.pop()?;
+                    let index = self.pop()?;
+                    let object = self.pop()?;
+                    let value = interpreter.set_index(object, index, value, &chunk.tokens[bracket as usize])?;
+                    self.stack.push(value);
+                }
+                OpCode::CheckKey(brace) => {
+                    MapKey::from_object(&chunk.tokens[brace as usize], self.peek()?)?;
+                }
+                OpCode::List { bracket, len } => {
+                    let elements = self.pop_n(len)?;
+                    let list = interpreter.new_list(elements, Some(chunk.tokens[bracket as usize].span))?;
+                    self.stack.push(list);
+                }
+                OpCode::Map { brace, len } => {
+                    let entries = self.pop_n(len * 2)?;
+                    let mut map = LoxMap::new();
+                    for entry in entries.chunks(2) {
+                        let key = MapKey::from_object(&chunk.tokens[brace as usize], &entry[0])?;
+                        map.insert(key, entry[1].clone());
+                    }
+                    let map = interpreter.new_map(map, Some(chunk.tokens[brace as usize].span))?;
+                    self.stack.push(map);
+                }
+                OpCode::Interpolate { token, len } => {
+                    let parts = self.pop_n(len)?;
+                    let value: String = parts.iter().map(|part| part.to_string()).collect();
+                    let value = interpreter.new_string(value, Some(chunk.tokens[token as usize].span))?;
+                    self.stack.push(value);
+                }
+                OpCode::Return => {
+                    let value = self.pop()?;
+                    self.close_upvalues(base);
+                    self.stack.truncate(base);
+                    self.frames.pop();
+                    if self.frames.is_empty() {
+                        return Ok(value);
+                    }
+                    interpreter.pop_frame();
+                    self.stack.push(value);
+                    (chunk, upvalues, ip, base) = self.current();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Lux;
+
+    // Runs `source` with both backends and checks they agree on `names`.
+    fn same_results(source: &str, names: &[&str]) -> Vec<Object> {
+        let mut tree = Lux::new();
+        tree.run(source).unwrap();
+        let mut vm = Lux::new();
+        vm.use_vm = true;
+        vm.run(source).unwrap();
+        names
+            .iter()
+            .map(|name| {
+                let value = vm.global(name);
+                assert_eq!(value.to_string(), tree.global(name).to_string(), "{}", name);
+                value
+            })
+            .collect()
+    }
+
+    #[test]
+    fn closures_and_loops_match_the_tree_walker() {
+        let values = same_results(
+            "fun counter() { var n = 0; return fun () { n = n + 1; return n; }; }
+            var c = counter(); c(); var count = c();
+            var fns = [];
+            for (var i = 0; i < 5; i = i + 1) {
+                if (i == 1) continue;
+                if (i == 4) break;
+                var j = i * 10;
+                fns.push(fun () { return j; });
+            }
+            var seen = \"\";
+            for (var k = 0; k < fns.len(); k = k + 1) seen = \"${seen}${fns[k]()},\";
+            var logic = nil or (false and 1) or \"x\";
+            fun pair() {
+                var shared = \"\";
+                fun add(s) { shared = shared + s; }
+                fun get() { return shared; }
+                return [add, get];
+            }
+            var p = pair(); p[0](\"a\"); p[0](\"b\");
+            var both = p[1]();",
+            &["count", "seen", "logic", "both"],
+        );
+        assert_eq!(values[0], Object::Num(2.0));
+        assert_eq!(values[1], Object::Str("0,20,30,".into()));
+        assert_eq!(values[3], Object::Str("ab".into()));
+    }
+
+    #[test]
+    fn classes_and_collections_match_the_tree_walker() {
+        let values = same_results(
+            "class A { init(x) { this.x = x; } get() { return this.x; } }
+            class B < A { get() { var f = fun () { return super.get() * 2; }; return f(); } }
+            var b = B(21); var doubled = b.get(); var again = b.init(1).x;
+            var local;
+            { class L { init(n) { this.n = n; } next() { return L(this.n + 1); } } local = L(1).next().n; }
+            var m = {\"a\": 1, 2: [3, 4]}; m[\"a\"] = m[2][1] + 1;
+            var total = m[\"a\"] + m[2][0];",
+            &["doubled", "again", "total", "m", "local"],
+        );
+        assert_eq!(values[0], Object::Num(42.0));
+        assert_eq!(values[2], Object::Num(8.0));
+        assert_eq!(values[4], Object::Num(2.0));
+    }
+
+    #[test]
+    fn runtime_errors_match_the_tree_walker() {
+        for source in [
+            "var x = 1 + nil;",
+            "fun f() { { return g(); } } f();",
+            "1.field = 2;",
+            "var m = {[1]: 2};",
+            "class A < \"x\" {}",
+            "fun f(a) {} f();",
+            "class A { init(a) {} } A();",
+            "class A { m() { return this.x; } } fun f() { return A().m(); } f();",
+        ] {
+            let mut tree = Lux::new();
+            let mut vm = Lux::new();
+            vm.use_vm = true;
+            let expected = tree.run(source).unwrap_err();
+            let errors = vm.run(source).unwrap_err();
+            assert_eq!(format!("{:?}", errors), format!("{:?}", expected), "{}", source);
+        }
+    }
+
+    #[test]
+    fn a_failed_script_leaves_the_globals_environment_in_place() {
+        let mut lux = Lux::new();
+        lux.use_vm = true;
+        assert!(lux.run("{ var a = 1; { var b = nil + a; } }").is_err());
+        lux.run("var after = 3;").unwrap();
+        assert_eq!(lux.global("after"), Object::Num(3.0));
+    }
+
+    // Run with `cargo test --release -- --ignored --nocapture fib_benchmark`
+    // to compare with the tree walker. On the machine this was written on,
+    // the tree walker took about 150 ms and the VM about 75 ms; when the VM
+    // kept its locals in environments and recursed for each call, it took
+    // about as long as the tree walker.
+    #[test]
+    #[ignore]
+    fn vm_fib_benchmark() {
+        let mut lux = Lux::new();
+        lux.use_vm = true;
+        let start = std::time::Instant::now();
+        lux.run(
+            "fun fib(n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); }
+            var result = fib(25);",
+        )
+        .unwrap();
+        println!("fib(25) on the VM took {:?}", start.elapsed());
+        assert_eq!(lux.global("result"), Object::Num(75025.0));
+    }
+}